@@ -2,18 +2,18 @@
 
 use arcropolis_api::arc_callback;
 use arcropolis_api::hash40;
-use randl_core::{RandlFile, RandlEntry, Set, Value};
+use randl_core::{RandlFile, RandlEntry, SeedCode, Set};
 
 use randl_core::prc::{self, hash40::{self, Hash40}};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::io::Cursor;
 use std::collections::HashMap;
 
 #[arc_callback]
 fn prc_callback(hash: u64, mut data: &mut [u8]) -> Option<usize> {
-    let (entry, sets) = RANDL_LOOKUP.get(&Hash40(hash))?;
+    let (path, entries) = RANDL_LOOKUP.get(&Hash40(hash))?;
 
     let len = data.len();
 
@@ -21,7 +21,14 @@ fn prc_callback(hash: u64, mut data: &mut [u8]) -> Option<usize> {
 
     let mut prc_file = prc::read_stream(&mut Cursor::new(&mut data)).map_err(|err| dbg!(err)).ok()?;
 
-    entry.apply(&mut prc_file, sets).map_err(|err| dbg!(err)).ok()?;
+    for (entry, sets) in entries {
+        if let Err(errors) = entry.apply_seeded(path, &mut prc_file, sets, *SEED) {
+            for err in &errors {
+                println!("[randl] {}", err);
+            }
+            return None;
+        }
+    }
 
     prc::write_stream(&mut Cursor::new(data), &prc_file).map_err(|err| dbg!(err)).ok()?;
 
@@ -29,101 +36,86 @@ fn prc_callback(hash: u64, mut data: &mut [u8]) -> Option<usize> {
 }
 
 const CONFIG_FOLDER: &str = "sd:/ultimate/randl";
+const SEED_FILE: &str = "sd:/ultimate/randl/seed.txt";
+const LAST_SEED_FILE: &str = "sd:/ultimate/randl/last_seed.txt";
+const LABELS_FILE: &str = "sd:/ultimate/randl/ParamLabels.csv";
 
 type EntryAndSets = (&'static RandlEntry, &'static HashMap<String, Set>);
 
 lazy_static::lazy_static! {
-    #[derive(Clone)]
-    static ref RANDL_FILES: Vec<RandlFile> = fs::read_dir(CONFIG_FOLDER).unwrap()
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            let is_file = entry.file_type().ok()?.is_file();
-            let path = entry.path();
-            let is_kdl = path.extension().map(|ext| ext == "kdl").unwrap_or(false);
-
-            if is_file && is_kdl {
-                RandlFile::open(path).ok()
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    static ref RANDL_LOOKUP: HashMap<Hash40, EntryAndSets> = RANDL_FILES.iter()
-        .map(|file| {
-            file.entries.iter()
-                .map(move |entry| {
-                    PathIter::new(&entry.prc_name, &file.sets)
-                        .map(move |hash| (hash, (entry, &file.sets)))
-                })
-                .flatten()
+    // use the seed (or seed code) in seed.txt if there is one, otherwise pick one at random
+    static ref SEED: u64 = fs::read_to_string(SEED_FILE)
+        .ok()
+        .and_then(|seed| {
+            let seed = seed.trim();
+            seed.parse().ok().or_else(|| {
+                let code: SeedCode = seed.parse().map_err(|err| dbg!(err)).ok()?;
+                if !code.matches(&RANDL_FILES) {
+                    println!("[randl] warning: seed code was made with different configs, results will not match");
+                }
+                Some(code.seed)
+            })
         })
-        .flatten()
-        .collect();
-}
+        .unwrap_or_else(randl_core::rand::random);
 
-enum PathIter<'a> {
-    Once(&'a str),
-    Templated {
-        prefix: &'a str,
-        suffix: &'a str,
-        templates: std::slice::Iter<'a, Value>,
-    },
-    None,
-}
-
-impl<'a> Iterator for PathIter<'a> {
-    type Item = Hash40;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self {
-            PathIter::Once(path) => {
-                let hash = hash40::hash40(path);
-                *self = PathIter::None;
-                Some(hash)
-            }
-            PathIter::Templated { prefix, suffix, templates } => {
-                templates.next()
-                    .map(|middle| {
-                        hash40::hash40(&match middle {
-                            Value::Int(i) => format!(
-                                "{}{}{}",
-                                prefix,
-                                i,
-                                suffix,
-                            ),
-                            Value::String(s) => format!(
-                                "{}{}{}",
-                                prefix,
-                                s,
-                                suffix,
-                            ),
-                            _ => panic!(
-                                "Invalid type in templated set. only Int and String are allowed"
-                            )
-                        })
-                    })
+    #[derive(Clone)]
+    static ref RANDL_FILES: Vec<RandlFile> = {
+        let mut paths: Vec<PathBuf> = fs::read_dir(CONFIG_FOLDER).unwrap()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let is_file = entry.file_type().ok()?.is_file();
+                let path = entry.path();
+                let is_kdl = path.extension().map(|ext| ext == "kdl").unwrap_or(false);
+
+                if is_file && is_kdl {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        // read_dir order isn't guaranteed, sort so entries always apply in the same order
+        paths.sort();
+
+        paths.into_iter()
+            .filter_map(|path| match RandlFile::open(&path) {
+                Ok(file) => Some(file),
+                Err(errors) => {
+                    for err in &errors {
+                        println!("[randl] {}", err);
+                    }
+                    None
+                }
+            })
+            .collect()
+    };
+
+    // prc path hash => (prc path, every entry that applies to it)
+    static ref RANDL_LOOKUP: HashMap<Hash40, (String, Vec<EntryAndSets>)> = {
+        let mut lookup: HashMap<Hash40, (String, Vec<EntryAndSets>)> = HashMap::new();
+        for file in RANDL_FILES.iter() {
+            for entry in file.entries.iter() {
+                // an entry listed twice under one hash would be applied twice, compounding any
+                // relative returns
+                let mut hashes = Vec::new();
+                for path in entry.prc_paths(&file.sets) {
+                    let hash = hash40::hash40(&path);
+                    if hashes.contains(&hash) {
+                        continue;
+                    }
+                    hashes.push(hash);
+
+                    lookup.entry(hash)
+                        .or_insert_with(|| (path, Vec::new()))
+                        .1
+                        .push((entry, &file.sets));
+                }
             }
-            PathIter::None => None,
         }
-    }
-}
 
-impl<'a> PathIter<'a> {
-    fn new(path_pattern: &'a str, sets: &'a HashMap<String, Set>) -> Self {
-        match path_pattern.matches('{').count() {
-            0 => Self::Once(path_pattern),
-            1 => {
-                let (prefix, rest) = path_pattern.split_once('{').unwrap();
-                let (set, suffix) = rest.split_once('}').unwrap();
-
-                let set = sets.get(set).unwrap_or_else(|| panic!("No set {:?} found.", set));
-
-                PathIter::Templated { prefix, suffix, templates: set.0.iter() }
-            },
-            _ => panic!("Cannot ")
-        }
-    }
+        lookup
+    };
 }
 
 #[skyline::main(name = "randl")]
@@ -133,29 +125,23 @@ pub fn main() {
     if !Path::new(CONFIG_FOLDER).is_dir() {
         fs::create_dir(CONFIG_FOLDER).unwrap();
     }
-    lazy_static::initialize(&RANDL_FILES);
-    lazy_static::initialize(&RANDL_LOOKUP);
-
-    for files in RANDL_FILES.to_vec().into_iter() {
-        let entries = files.entries;
-        for entry in entries {
-            let path_ = entry.prc_name;
-            let hash = hash40(&path_);
-            prc_callback::install(hash, 1000000);
+    // field patterns like `*_speed` can only match fields with a known name
+    if Path::new(LABELS_FILE).is_file() {
+        match randl_core::load_labels(LABELS_FILE) {
+            Ok(count) => println!("[randl] loaded {} labels", count),
+            Err(err) => println!("[randl] labels could not be read: {}", err),
         }
     }
-}
-
-trait SplitOnceCompat {
-    fn split_once(&self, pattern: char) -> Option<(&str, &str)>;
-}
-
-impl SplitOnceCompat for str {
-    fn split_once(&self, pattern: char) -> Option<(&str, &str)> {
-        let pos: usize = self.find(pattern)?;
+    lazy_static::initialize(&RANDL_FILES);
+    lazy_static::initialize(&SEED);
+    lazy_static::initialize(&RANDL_LOOKUP);
 
-        let (before, after) = self.split_at(pos);
+    // save the code for this run so it can be shared or reproduced
+    let code = SeedCode::new(*SEED, &RANDL_FILES);
+    println!("[randl] seed code: {}", code);
+    let _ = fs::write(LAST_SEED_FILE, code.to_string());
 
-        Some((before, &after[1..]))
+    for (path, _) in RANDL_LOOKUP.values() {
+        prc_callback::install(hash40(path), 1000000);
     }
 }
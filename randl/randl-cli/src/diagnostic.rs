@@ -0,0 +1,72 @@
+use std::fmt::Write;
+
+use owo_colors::OwoColorize;
+use randl_core::ParseDiagnostic;
+
+/// Render a parse error with the line it's on and a caret under the offending node:
+///
+/// ```text
+/// error: Invalid return: Returns may not have more than one return value
+///   --> config.kdl:11:30
+///    |
+/// 11 |         chance percent="x" { return 1 2 }
+///    |                              ^^^^^^
+///    = in: file "x" > walk_speed > chance > return 1
+/// ```
+pub fn render(diag: &ParseDiagnostic) -> String {
+    let mut out = String::new();
+    writeln!(out, "{} {}", "error:".bright_red().bold(), diag.error.bold()).unwrap();
+
+    let span = match &diag.span {
+        Some(span) => span,
+        None => {
+            if let Some(file) = &diag.file {
+                writeln!(out, "  {} {}", "-->".bright_blue(), file).unwrap();
+            }
+            return out;
+        }
+    };
+
+    let gutter = " ".repeat(span.line.to_string().len());
+    let file = diag.file.as_deref().unwrap_or("<config>");
+    writeln!(out, "{}{} {}:{}", gutter, "-->".bright_blue(), file, span).unwrap();
+    writeln!(out, "{} {}", gutter, "|".bright_blue()).unwrap();
+    writeln!(
+        out,
+        "{} {} {}",
+        span.line.bright_blue(),
+        "|".bright_blue(),
+        span.source_line
+    )
+    .unwrap();
+
+    // keep tabs so the caret lines up with the source line
+    let indent: String = span
+        .source_line
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    writeln!(
+        out,
+        "{} {} {}{}",
+        gutter,
+        "|".bright_blue(),
+        indent,
+        "^".repeat(span.len).bright_red()
+    )
+    .unwrap();
+
+    if !diag.nodes.is_empty() {
+        writeln!(
+            out,
+            "{} {} in: {}",
+            gutter,
+            "=".bright_blue(),
+            diag.nodes.join(" > ")
+        )
+        .unwrap();
+    }
+
+    out
+}
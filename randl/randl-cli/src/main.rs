@@ -1,9 +1,14 @@
+use std::fs;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use owo_colors::OwoColorize;
 
-use randl_core::RandlFile;
+use randl_core::{RandlFile, SeedCode};
 use randl_core::prc;
+use randl_core::rand;
+
+mod diagnostic;
+mod report;
 
 #[derive(StructOpt)]
 struct Args {
@@ -16,37 +21,135 @@ struct Args {
 
     #[structopt(
         short, long,
-        help = "The file node to apply to the given prc file"
+        help = "The prc file being randomized, either as a path in game or a `file` node's name. Every `file` node for that path is applied"
     )]
     file: Option<String>,
+
+    #[structopt(
+        short, long,
+        help = "The seed to randomize with. A random one is picked and printed if not provided"
+    )]
+    seed: Option<u64>,
+
+    #[structopt(
+        short, long,
+        conflicts_with = "seed",
+        help = "A seed code to reproduce a run shared by someone else"
+    )]
+    code: Option<SeedCode>,
+
+    #[structopt(
+        long,
+        help = "Skip any params that fail to evaluate instead of leaving the prc unchanged"
+    )]
+    best_effort: bool,
+
+    #[structopt(
+        long,
+        help = "A ParamLabels.csv-style list of field names, used to match field patterns like `*_speed`"
+    )]
+    labels: Option<PathBuf>,
+
+    #[structopt(long, help = "Write every change made to a JSON file")]
+    report: Option<PathBuf>,
+
+    #[structopt(long, help = "Write every change made to a human-readable spoiler log")]
+    spoiler: Option<PathBuf>,
 }
 
 fn main() {
     let args = Args::from_args();
     let randl = match RandlFile::open(args.kdl) {
         Ok(randl) => randl,
-        Err(e) => {
-            println!("{} {}", "Parse Error:".bright_red(), e.bright_red());
+        Err(errors) => {
+            for e in &errors {
+                println!("{}", diagnostic::render(e));
+            }
             return
         }
     };
 
-    let idx = match args.file {
-        Some(file) => match randl.entries.iter().position(|entry| entry.prc_name == file) {
-            Some(x) => x,
-            None => {
+    // the path of the prc in game is part of the seed, so use the one given if it matches
+    let found = args.file.as_ref().and_then(|file| {
+        randl.entries.iter().enumerate().find_map(|(i, entry)| {
+            if entry.matches_path(file, &randl.sets) {
+                Some((i, Some(file.clone())))
+            } else if entry.prc_name == *file {
+                Some((i, None))
+            } else {
+                None
+            }
+        })
+    });
+    let (idx, prc_path) = match found {
+        Some(found) => found,
+        None => {
+            if args.file.is_some() {
                 println!("{}", "Warning: file not found, defaulting to first `file` node.".bright_yellow());
-                0
             }
+            (0, None)
         }
-        None => 0,
     };
 
-    let mut prc = prc::open(&args.prc).unwrap();
-    if let Err(e) = randl.entries[idx].apply(&mut prc, &randl.sets) {
-        println!("{} {}", "Eval Error:".bright_red(), e.bright_red());
-        return
+    // a templated name isn't a path in game, so seed with one of its paths like the plugin would
+    let prc_path = prc_path.unwrap_or_else(|| {
+        let entry = &randl.entries[idx];
+        let paths = entry.prc_paths(&randl.sets);
+        if paths.len() > 1 {
+            println!("{}", format!(
+                "Warning: `{}` applies to {} files, using `{}`. Pass a path in game to `--file` to pick another.",
+                entry.prc_name, paths.len(), paths[0],
+            ).bright_yellow());
+        }
+        paths.into_iter().next().unwrap_or_else(|| entry.prc_name.clone())
+    });
+
+    let files = std::slice::from_ref(&randl);
+    let seed = match args.code {
+        Some(code) => {
+            if !code.matches(files) {
+                println!("{}", "Warning: seed code was made with a different config, results will not match.".bright_yellow());
+            }
+            code.seed
+        }
+        None => args.seed.unwrap_or_else(rand::random),
+    };
+    let code = SeedCode::new(seed, files);
+    println!("Seed: {}", seed);
+    println!("Seed code: {}", code);
+
+    if let Some(path) = &args.labels {
+        if let Err(e) = randl_core::load_labels(path) {
+            println!("{}", format!("Warning: labels could not be read: {}", e).bright_yellow());
+        }
     }
 
+    // apply every entry for the path in the order they're written, the same as the plugin
+    let mut prc = prc::open(&args.prc).unwrap();
+    let report = if args.best_effort {
+        let (report, errors) = randl.apply_best_effort(&prc_path, &mut prc, seed);
+        for e in errors {
+            println!("{} {}", "Eval Error:".bright_red(), e.bright_red());
+        }
+        report
+    } else {
+        match randl.apply_with_report(&prc_path, &mut prc, seed) {
+            Ok(report) => report,
+            Err(errors) => {
+                for e in &errors {
+                    println!("{} {}", "Eval Error:".bright_red(), e.bright_red());
+                }
+                return
+            }
+        }
+    };
+
     prc::save(&args.prc_out, &prc).unwrap();
+
+    if let Some(path) = args.report {
+        fs::write(path, report::to_json(&code, &report)).unwrap();
+    }
+    if let Some(path) = args.spoiler {
+        fs::write(path, report::to_spoiler_log(&code, &report)).unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,102 @@
+use std::fmt::Write;
+
+use randl_core::prc::ParamKind;
+use randl_core::{DisplayParam, Report, SeedCode};
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_param(param: &ParamKind) -> String {
+    match param {
+        ParamKind::Float(f) if !f.is_finite() => "null".into(),
+        ParamKind::Str(s) => json_string(s),
+        ParamKind::Hash(_) | ParamKind::List(_) | ParamKind::Struct(_) => {
+            json_string(&DisplayParam(param).to_string())
+        }
+        _ => DisplayParam(param).to_string(),
+    }
+}
+
+/// Every change as a JSON object:
+///
+/// ```json
+/// {
+///   "seed_code": "3ZK1-8Q0M-TT2C-9VXA",
+///   "changes": [
+///     {"file": "...", "param": "attack_data.3.start_frame", "old": 5, "new": 7, "chances": [0]}
+///   ],
+///   "skipped": [
+///     {"file": "...", "path": "attack_data.*?.start_frame", "count": 2}
+///   ]
+/// }
+/// ```
+pub fn to_json(code: &SeedCode, report: &Report) -> String {
+    let changes = &report.changes;
+    let mut out = String::new();
+    writeln!(out, "{{").unwrap();
+    writeln!(out, "  \"seed_code\": {},", json_string(&code.to_string())).unwrap();
+    writeln!(out, "  \"changes\": [").unwrap();
+    for (i, change) in changes.iter().enumerate() {
+        let chances: Vec<String> = change.chances.iter().map(|c| c.to_string()).collect();
+        write!(
+            out,
+            "    {{\"file\": {}, \"param\": {}, \"old\": {}, \"new\": {}, \"chances\": [{}]}}",
+            json_string(&change.prc_path),
+            json_string(&change.param),
+            json_param(&change.old),
+            json_param(&change.new),
+            chances.join(", "),
+        )
+        .unwrap();
+        out.push_str(if i + 1 == changes.len() { "\n" } else { ",\n" });
+    }
+    writeln!(out, "  ],").unwrap();
+    writeln!(out, "  \"skipped\": [").unwrap();
+    for (i, skipped) in report.skipped.iter().enumerate() {
+        write!(
+            out,
+            "    {{\"file\": {}, \"path\": {}, \"count\": {}}}",
+            json_string(&skipped.prc_path),
+            json_string(&skipped.path),
+            skipped.count,
+        )
+        .unwrap();
+        out.push_str(if i + 1 == report.skipped.len() { "\n" } else { ",\n" });
+    }
+    writeln!(out, "  ]").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    out
+}
+
+pub fn to_spoiler_log(code: &SeedCode, report: &Report) -> String {
+    let mut out = String::new();
+    writeln!(out, "Seed code: {}", code).unwrap();
+    writeln!(out).unwrap();
+    for change in &report.changes {
+        writeln!(out, "{}", change).unwrap();
+    }
+    if !report.skipped.is_empty() {
+        writeln!(out).unwrap();
+        for skipped in &report.skipped {
+            writeln!(out, "{}", skipped).unwrap();
+        }
+    }
+
+    out
+}
@@ -1,9 +1,156 @@
+use std::fmt;
+
 use kdl::KdlError;
+
+use crate::span::{NodeSpan, Span};
+
+/// Every error hit while parsing or applying a file, so they can all be fixed in one go rather
+/// than one per run
+#[derive(Debug, Clone)]
+pub struct Diagnostics<E>(pub Vec<E>);
+
+impl<E> Diagnostics<E> {
+    pub fn iter(&self) -> std::slice::Iter<'_, E> {
+        self.0.iter()
+    }
+
+    /// Parse every item, collecting the errors from all of them rather than stopping at the first
+    pub(crate) fn collect<I, T>(items: I) -> Result<Vec<T>, Self>
+    where
+        I: IntoIterator<Item = Result<T, Self>>,
+    {
+        let mut oks = Vec::new();
+        let mut errors = Vec::new();
+        for item in items {
+            match item {
+                Ok(ok) => oks.push(ok),
+                Err(Diagnostics(mut errs)) => errors.append(&mut errs),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(oks)
+        } else {
+            Err(Diagnostics(errors))
+        }
+    }
+}
+
+impl<E> From<E> for Diagnostics<E> {
+    fn from(err: E) -> Self {
+        Diagnostics(vec![err])
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Diagnostics<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, err) in self.0.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", err)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Diagnostics<E> {}
+
+impl<'a, E> IntoIterator for &'a Diagnostics<E> {
+    type Item = &'a E;
+    type IntoIter = std::slice::Iter<'a, E>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// A parse error along with where in the config it happened
+#[derive(Debug, Clone)]
+pub struct ParseDiagnostic {
+    pub error: ParseError,
+    /// The config the error is in, if it was read from a file
+    pub file: Option<String>,
+    /// The node the error is in and every node containing it, outermost first
+    pub nodes: Vec<String>,
+    pub span: Option<Span>,
+    /// The index of each node leading to the one the error is in, outermost first
+    pub(crate) at: Vec<usize>,
+}
+
+impl ParseDiagnostic {
+    /// Fill in the span and node names using the nodes found in the source
+    pub(crate) fn locate(&mut self, src: &str, spans: &[NodeSpan]) {
+        if let ParseError::ParseFail(err) = &self.error {
+            let mut offset = err.offset.min(src.len());
+            while !src.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            self.span = Some(Span::new(src, offset, offset + 1));
+            return;
+        }
+
+        let mut spans = spans;
+        let mut node = None;
+        for &i in &self.at {
+            match spans.get(i) {
+                Some(span) => {
+                    self.nodes.push(span.label.clone());
+                    spans = &span.children;
+                    node = Some(span);
+                }
+                None => break,
+            }
+        }
+
+        self.span = node.map(|node| Span::new(src, node.start, node.end));
+    }
+}
+
+impl From<ParseError> for ParseDiagnostic {
+    fn from(error: ParseError) -> Self {
+        ParseDiagnostic {
+            error,
+            file: None,
+            nodes: Vec::new(),
+            span: None,
+            at: Vec::new(),
+        }
+    }
+}
+
+impl From<ParseError> for Diagnostics<ParseDiagnostic> {
+    fn from(error: ParseError) -> Self {
+        Diagnostics(vec![error.into()])
+    }
+}
+
+/// A single line, `file:line:column: error (in node > node)`, for logs
+impl fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, &self.span) {
+            (Some(file), Some(span)) => write!(f, "{}:{}: ", file, span)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            (None, Some(span)) => write!(f, "{}: ", span)?,
+            (None, None) => {}
+        }
+        write!(f, "{}", self.error)?;
+        if !self.nodes.is_empty() {
+            write!(f, " (in `{}`)", self.nodes.join(" > "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseDiagnostic {}
+
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum ParseError {
     #[error("the file could not be read")]
     FileReadFail,
-    #[error("could not parse: invalid KDL")]
+    #[error("could not parse: {}", .0.kind)]
     ParseFail(KdlError),
     #[error("The value did not match the specified type")]
     IncorrectType,
@@ -11,8 +158,10 @@ pub enum ParseError {
     InvalidType,
     #[error("Value must follow type declaration in set")]
     NoValue,
-    #[error("No percent provided for the given chance expression")]
+    #[error("No percent or weight provided for the given chance expression")]
     NoPercent,
+    #[error("Chance percents add up to {0}%, which is over 100%. Use `weight` to have them scaled automatically")]
+    ChanceOverflow(f64),
     #[error("Chance expressions cannot be mixed with other expressions")]
     MixedExprs,
     #[error("Too many expressions were provided. Only multiple `chance` expessions may be used")]
@@ -21,30 +170,88 @@ pub enum ParseError {
     ExprRequired,
     #[error("Invalid return: {0}")]
     InvalidReturn(&'static str),
+    #[error("Invalid distribution: {0}")]
+    InvalidDistribution(&'static str),
+    #[error("Invalid path `{0}`: {1}")]
+    InvalidPath(String, &'static str),
+    #[error("Invalid `when`: {0}")]
+    InvalidWhen(&'static str),
     #[error("Invalid `value` statement: {0}")]
     InvalidValueStmt(&'static str),
     #[error("Invalid chance: {0}")]
     InvalidChance(&'static str),
     #[error("Invalid expression: {0}")]
     InvalidExpr(String),
+    #[error("Invalid param entry: {0}")]
+    InvalidPrcEntry(&'static str),
+    #[error("`mode` must be either \"each\" or \"once\"")]
+    InvalidMode,
+    #[error("Invalid group: {0}")]
+    InvalidGroup(&'static str),
     #[error("Invalid top-level entry: {0}")]
     InvalidRandlEntry(&'static str),
+    #[error("The set {0:?} used in the file name could not be found")]
+    UnknownTemplateSet(String),
+    #[error("The set {0:?} used in the file name has no `int` or `string` values to fill it in with")]
+    EmptyTemplateSet(String),
+    #[error("Invalid set: {0}")]
+    InvalidSet(&'static str),
     #[error("Only `value` nodes allowed in sets, not `{0}`")]
     NonValueInSet(String),
+    #[error("Invalid formula: {0}")]
+    InvalidFormula(String),
+}
+
+/// An eval error along with the param it happened on, reading like
+/// `fighter/mario/param/vl.prc: attack_data.3.start_frame: cannot assign float to u8`
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("{prc_path}: {param}: {error}")]
+pub struct EvalDiagnostic {
+    pub error: EvalError,
+    /// The path of the prc file in game
+    pub prc_path: String,
+    /// The param being randomized, with any wildcards filled in if they were resolved
+    pub param: String,
 }
 
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum EvalError {
     #[error("Index {0} was outside of the bounds of the param list")]
     IndexOutOfBounds(usize),
+    #[error("Index {0} is out of bounds for a list of length {1}")]
+    SelectionOutOfBounds(i64, usize),
     #[error("{0}")]
     MissingField(String),
     #[error("Invalid field: {0}")]
     InvalidField(&'static str),
-    #[error("{0} cannot be assigned to {1}")]
+    #[error("cannot assign {0} to {1}")]
     InvalidAssignment(&'static str, &'static str),
-    #[error("The given value was too large to fit")]
-    IntTooBig,
+    #[error("{0} does not fit in {1}")]
+    IntTooBig(i64, &'static str),
+    #[error("`{0}` didn't match any field, and {1} fields have no known name. Load a labels file (like ParamLabels.csv) so they can be matched")]
+    UnlabeledFields(String, usize),
     #[error("The set {0:?} could not be found")]
     InvalidSet(String),
+    #[error("Relative returns can only be applied to numeric params, not {0}")]
+    NonNumericOriginal(&'static str),
+    #[error("Division by zero in formula")]
+    DivideByZero,
+    #[error("The range {0}..{1} is empty")]
+    EmptyRange(f64, f64),
+    #[error("Every value of a `unique` set has already been drawn, add more or use `refill=true`")]
+    SetExhausted,
+    #[error("cannot compare {0} to {1}")]
+    InvalidComparison(&'static str, &'static str),
+    #[error("Only single values can be shuffled, not a {0}")]
+    CannotShuffle(&'static str),
+    #[error("Invalid reference: {0}")]
+    InvalidRef(String),
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum SeedCodeError {
+    #[error("Seed codes must be 16 characters long")]
+    InvalidLength,
+    #[error("{0:?} is not a valid seed code character")]
+    InvalidChar(char),
 }
@@ -1,12 +1,19 @@
-use rand::prelude::*;
+use rand::RngCore;
+use std::cell::Cell;
 use std::convert::TryInto;
 
 use super::*;
+use crate::labels::{glob_matches, label_of};
+use crate::rng::{
+    gen_float, gen_float_range, gen_gamma, gen_index, gen_int_range, gen_normal,
+    gen_weighted_index, SeedHasher,
+};
 use prc::{ParamKind, ParamStruct};
 
 type Result<T> = std::result::Result<T, EvalError>;
+type Errors = Diagnostics<EvalDiagnostic>;
 
-fn struct_lookup<'a>(param: &'a mut ParamStruct, field: &str) -> Result<&'a mut ParamKind> {
+fn struct_lookup<'a>(param: &'a ParamStruct, field: &str) -> Result<(usize, &'a ParamKind)> {
     let hash = prc::hash40::hash40(field);
     let index = param
         .0
@@ -14,249 +21,1902 @@ fn struct_lookup<'a>(param: &'a mut ParamStruct, field: &str) -> Result<&'a mut
         .position(|x| x.0 == hash)
         .ok_or_else(|| EvalError::MissingField(format!("Field `{}` is missing", field)))?;
 
-    Ok(param.0.get_mut(index).map(|x| &mut x.1).unwrap())
+    Ok((index, &param.0[index].1))
 }
 
-fn get_path_param_kind<'a>(
-    param: &'a mut ParamKind,
+/// The params a path resolved to, or the error along with the deepest point in the path that
+/// could be resolved
+type PathResult = std::result::Result<Vec<ParamPath>, (EvalError, ParamPath)>;
+
+/// Combine the matches for each child of a wildcard. Lenient wildcards skip any children the
+/// rest of the path doesn't resolve for, rather than failing the whole path.
+fn collect_matches<I: Iterator<Item = PathResult>>(
+    children: I,
+    lenient: bool,
+    skipped: &Cell<usize>,
+) -> PathResult {
+    let mut matches = Vec::new();
+    for child in children {
+        match child {
+            Ok(mut child) => matches.append(&mut child),
+            Err(_) if lenient => skipped.set(skipped.get() + 1),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(matches)
+}
+
+fn get_path_param_kind(
+    param: &ParamKind,
     mut path: PrcPathSlice,
-) -> Result<Vec<&'a mut ParamKind>> {
+    at: ParamPath,
+    skipped: &Cell<usize>,
+) -> PathResult {
     let old_path = path.clone();
     match path.pop_front() {
         Some(PrcPathComponent::Field(field)) => match param {
-            ParamKind::Struct(param) => get_path_struct(param, old_path),
+            ParamKind::Struct(param) => get_path_struct(param, old_path, at, skipped),
 
-            _ => Err(EvalError::MissingField(format!(
-                "Cannot get field {:?} of non-struct param",
-                field,
-            ))),
+            _ => Err((
+                EvalError::MissingField(format!(
+                    "Cannot get field {:?} of non-struct param",
+                    field,
+                )),
+                at,
+            )),
+        },
+        Some(PrcPathComponent::Pattern(pattern)) => match param {
+            ParamKind::Struct(param) => get_path_struct(param, old_path, at, skipped),
+            _ => Err((
+                EvalError::MissingField(format!(
+                    "Cannot get fields matching `{}` of non-struct param",
+                    pattern,
+                )),
+                at,
+            )),
         },
         Some(&PrcPathComponent::Index(index)) => match param {
-            ParamKind::List(param) => get_path_param_kind(
-                param
-                    .0
-                    .get_mut(index)
-                    .ok_or_else(|| EvalError::IndexOutOfBounds(index))?,
-                path,
-            ),
-            _ => Err(EvalError::InvalidField(
-                "Cannot index into a non-list param",
+            ParamKind::List(param) => match param.0.get(index) {
+                Some(child) => {
+                    let at = at.join(ParamStep::Index(index), false);
+                    get_path_param_kind(child, path, at, skipped)
+                }
+                None => Err((EvalError::IndexOutOfBounds(index), at)),
+            },
+            _ => Err((
+                EvalError::InvalidField("Cannot index into a non-list param"),
+                at,
             )),
         },
-        Some(PrcPathComponent::Wildcard) => match param {
-            ParamKind::Struct(param) => {
-                let next = path;
-                param
-                    .0
-                    .iter_mut()
-                    .map(|x| get_path_param_kind(&mut x.1, next.clone()))
-                    .fold(Ok(Vec::new()), |mut vec, mut new| {
-                        let vec_ref = vec.as_mut().map_err(|x| x.clone())?;
-                        let new = new.as_mut().map_err(|x| x.clone())?;
-                        vec_ref.append(new);
-                        vec
-                    })
+        Some(PrcPathComponent::Select(selections)) => match param {
+            ParamKind::List(list) => {
+                let indices = match Selection::resolve_all(selections, list.0.len()) {
+                    Ok(indices) => indices,
+                    Err(err) => return Err((err, at)),
+                };
+                // a lone index only ever matches one param, so it isn't a wildcard
+                let wildcard = !matches!(selections.as_slice(), [Selection::Index(_)]);
+                let children = indices.into_iter().map(|i| {
+                    let at = at.join(ParamStep::Index(i), wildcard);
+                    get_path_param_kind(&list.0[i], path, at, skipped)
+                });
+
+                collect_matches(children, false, skipped)
             }
+            _ => Err((
+                EvalError::InvalidField("Cannot index into a non-list param"),
+                at,
+            )),
+        },
+        Some(component @ PrcPathComponent::Wildcard)
+        | Some(component @ PrcPathComponent::LenientWildcard)
+        | Some(component @ PrcPathComponent::Filter { .. }) => match param {
+            ParamKind::Struct(param) => get_path_struct(param, old_path, at, skipped),
             ParamKind::List(list) => {
                 let next = path;
-                list.0
-                    .iter_mut()
-                    .map(|x| get_path_param_kind(x, next.clone()))
-                    .fold(Ok(Vec::new()), |mut vec, mut new| {
-                        let vec_ref = vec.as_mut().map_err(|x| x.clone())?;
-                        let new = new.as_mut().map_err(|x| x.clone())?;
-                        vec_ref.append(new);
-                        vec
-                    })
-            }
-            _ => Err(EvalError::InvalidField(
-                "A wildcard can only be applied to a struct or list",
+                let lenient = component.is_lenient();
+                let children = list.0.iter().enumerate().map(|(i, x)| {
+                    let at = at.join(ParamStep::Index(i), true);
+                    match component.keeps(x) {
+                        Ok(true) => get_path_param_kind(x, next.clone(), at, skipped),
+                        Ok(false) => Ok(Vec::new()),
+                        Err(err) => Err((err, at)),
+                    }
+                });
+
+                collect_matches(children, lenient, skipped)
+            }
+            _ => Err((
+                EvalError::InvalidField("A wildcard can only be applied to a struct or list"),
+                at,
             )),
         },
-        None => Ok(Vec::from([param])),
+        Some(PrcPathComponent::RecursiveWildcard) => {
+            Ok(get_path_recursive(param, path, at, skipped))
+        }
+        None => Ok(Vec::from([at])),
     }
 }
 
-fn get_path_struct<'a>(
-    param: &'a mut ParamStruct,
+/// Match the rest of a path after a `**` against `param` and everything nested inside it,
+/// skipping anywhere it doesn't resolve
+fn get_path_recursive(
+    param: &ParamKind,
+    path: PrcPathSlice,
+    at: ParamPath,
+    skipped: &Cell<usize>,
+) -> Vec<ParamPath> {
+    let mut matches = get_path_param_kind(param, path, at.clone(), skipped).unwrap_or_default();
+    match param {
+        ParamKind::Struct(param) => {
+            for (i, x) in param.0.iter().enumerate() {
+                let at = at.join(ParamStep::Field(i, x.0), true);
+                matches.append(&mut get_path_recursive(&x.1, path, at, skipped));
+            }
+        }
+        ParamKind::List(list) => {
+            for (i, x) in list.0.iter().enumerate() {
+                let at = at.join(ParamStep::Index(i), true);
+                matches.append(&mut get_path_recursive(x, path, at, skipped));
+            }
+        }
+        _ => {}
+    }
+
+    matches
+}
+
+fn get_path_struct(
+    param: &ParamStruct,
     mut path: PrcPathSlice,
-) -> Result<Vec<&'a mut ParamKind>> {
+    at: ParamPath,
+    skipped: &Cell<usize>,
+) -> PathResult {
     match path.pop_front() {
         Some(PrcPathComponent::Field(field)) => {
-            let field = struct_lookup(param, field)?;
-            get_path_param_kind(field, path)
+            let (index, field) = match struct_lookup(param, field) {
+                Ok(found) => found,
+                Err(err) => return Err((err, at)),
+            };
+            let hash = param.0[index].0;
+            let at = at.join(ParamStep::Field(index, hash), false);
+            get_path_param_kind(field, path, at, skipped)
+        }
+        Some(PrcPathComponent::Pattern(pattern)) => {
+            let mut unlabeled = 0;
+            let mut indices = Vec::new();
+            for (i, x) in param.0.iter().enumerate() {
+                match label_of(x.0) {
+                    Some(name) if pattern.matches(&name) => indices.push(i),
+                    Some(_) => {}
+                    None => unlabeled += 1,
+                }
+            }
+            if indices.is_empty() && unlabeled != 0 {
+                return Err((EvalError::UnlabeledFields(pattern.to_string(), unlabeled), at));
+            }
+
+            let children = indices.into_iter().map(|i| {
+                let at = at.join(ParamStep::Field(i, param.0[i].0), true);
+                get_path_param_kind(&param.0[i].1, path, at, skipped)
+            });
+
+            collect_matches(children, false, skipped)
         }
-        Some(PrcPathComponent::Index(_)) => Err(EvalError::MissingField(
-            "Cannot index into a `ParamStruct`".into(),
+        Some(PrcPathComponent::Index(_)) | Some(PrcPathComponent::Select(_)) => Err((
+            EvalError::MissingField("Cannot index into a `ParamStruct`".into()),
+            at,
         )),
-        Some(PrcPathComponent::Wildcard) => {
+        Some(component @ PrcPathComponent::Wildcard)
+        | Some(component @ PrcPathComponent::LenientWildcard)
+        | Some(component @ PrcPathComponent::Filter { .. }) => {
             let next = path;
-            param
-                .0
-                .iter_mut()
-                .map(|x| get_path_param_kind(&mut x.1, next.clone()))
-                .fold(Ok(Vec::new()), |mut vec, mut new| {
-                    let vec_ref = vec.as_mut().map_err(|x| x.clone())?;
-                    let new = new.as_mut().map_err(|x| x.clone())?;
-                    vec_ref.append(new);
-                    vec
-                })
-        }
-        None => Err(EvalError::InvalidField(
-            "Path pointed to a struct, edit one field at a time.",
+            let lenient = component.is_lenient();
+            let children = param.0.iter().enumerate().map(|(i, x)| {
+                let at = at.join(ParamStep::Field(i, x.0), true);
+                match component.keeps(&x.1) {
+                    Ok(true) => get_path_param_kind(&x.1, next.clone(), at, skipped),
+                    Ok(false) => Ok(Vec::new()),
+                    Err(err) => Err((err, at)),
+                }
+            });
+
+            collect_matches(children, lenient, skipped)
+        }
+        Some(PrcPathComponent::RecursiveWildcard) => {
+            let mut matches = get_path_struct(param, path, at.clone(), skipped).unwrap_or_default();
+            for (i, x) in param.0.iter().enumerate() {
+                let at = at.join(ParamStep::Field(i, x.0), true);
+                matches.append(&mut get_path_recursive(&x.1, path, at, skipped));
+            }
+
+            Ok(matches)
+        }
+        None => Err((
+            EvalError::InvalidField("Path pointed to a struct, edit one field at a time."),
+            at,
         )),
     }
 }
 
-fn e(a: &'static str, b: &'static str) -> Result<()> {
-    Err(EvalError::InvalidAssignment(a, b))
+impl FieldPattern {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            FieldPattern::Glob(glob) => glob_matches(glob, name),
+            FieldPattern::Regex(regex) => regex.is_match(name),
+        }
+    }
 }
 
-impl RandlEntry {
-    pub fn apply(&self, file: &mut ParamStruct, sets: &HashMap<String, Set>) -> Result<()> {
-        for field in self.prc_fields.iter() {
-            let to_edit = get_path_struct(file, field.path.as_ref())?;
+impl PrcPathComponent {
+    /// Whether a wildcard matches the given child, which is always true without filters
+    fn keeps(&self, child: &ParamKind) -> Result<bool> {
+        match self {
+            PrcPathComponent::Filter { filters, .. } => {
+                for filter in filters {
+                    if !filter.condition.test(filter.lookup(child)?)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+}
 
-            for param in to_edit {
-                let val = field.expr.eval(sets)?;
+impl Selection {
+    /// Every index the selections pick out of a list of length `len`, in order and without
+    /// repeats. Single indices have to be in bounds, while ranges are cut short at the ends.
+    fn resolve_all(selections: &[Selection], len: usize) -> Result<Vec<usize>> {
+        // negative indices count back from the end
+        let from_end = |index: i64| if index < 0 { index + len as i64 } else { index };
+        let clamp = |index: i64| from_end(index).max(0).min(len as i64) as usize;
 
-                match (param, val) {
-                    (_, Value::Original) => {}
-                    (ParamKind::Bool(b), Value::Bool(new)) => {
-                        *b = new;
-                    }
-                    (ParamKind::I8(i), Value::Int(new)) => {
-                        *i = new.try_into().map_err(|_| EvalError::IntTooBig)?;
-                    }
-                    (ParamKind::U8(i), Value::Int(new)) => {
-                        *i = new.try_into().map_err(|_| EvalError::IntTooBig)?;
-                    }
-                    (ParamKind::I16(i), Value::Int(new)) => {
-                        *i = new.try_into().map_err(|_| EvalError::IntTooBig)?;
-                    }
-                    (ParamKind::U16(i), Value::Int(new)) => {
-                        *i = new.try_into().map_err(|_| EvalError::IntTooBig)?;
-                    }
-                    (ParamKind::I32(i), Value::Int(new)) => {
-                        *i = new.try_into().map_err(|_| EvalError::IntTooBig)?;
-                    }
-                    (ParamKind::U32(i), Value::Int(new)) => {
-                        *i = new.try_into().map_err(|_| EvalError::IntTooBig)?;
+        let mut indices = Vec::new();
+        for selection in selections {
+            match *selection {
+                Selection::Index(index) => {
+                    let resolved = from_end(index);
+                    if resolved < 0 || resolved >= len as i64 {
+                        return Err(EvalError::SelectionOutOfBounds(index, len));
                     }
-                    (ParamKind::Float(f), Value::Int(new)) => {
-                        *f = new as f32;
-                    }
-                    (ParamKind::Float(f), Value::Float(new)) => {
-                        *f = new as f32;
-                    }
-                    (ParamKind::Hash(h), Value::Int(new)) => {
-                        *h = Hash40(new as u64);
-                    }
-                    (ParamKind::Hash(h), Value::String(ref s)) => {
-                        *h = prc::hash40::hash40(&s);
-                    }
-                    (ParamKind::Hash(h), Value::Hash40(new)) => {
-                        *h = new;
-                    }
-                    (ParamKind::Str(s), Value::String(ref new)) => {
-                        *s = new.clone();
+                    indices.push(resolved as usize);
+                }
+                Selection::Range(start, end) => {
+                    let start = start.map_or(0, clamp);
+                    let end = end.map_or(len, clamp);
+                    indices.extend(start..end.max(start));
+                }
+            }
+        }
+
+        indices.sort_unstable();
+        indices.dedup();
+        Ok(indices)
+    }
+}
+
+impl Filter {
+    /// Find the field the filter checks within a child
+    fn lookup<'a>(&self, child: &'a ParamKind) -> Result<&'a ParamKind> {
+        let mut param = child;
+        for component in self.path.0.iter() {
+            param = match (component, param) {
+                (PrcPathComponent::Field(field), ParamKind::Struct(param)) => {
+                    struct_lookup(param, field)?.1
+                }
+                (&PrcPathComponent::Index(index), ParamKind::List(list)) => {
+                    list.0.get(index).ok_or(EvalError::IndexOutOfBounds(index))?
+                }
+                (PrcPathComponent::Field(field), _) => {
+                    return Err(EvalError::MissingField(format!(
+                        "Cannot get field {:?} of non-struct param",
+                        field,
+                    )))
+                }
+                (PrcPathComponent::Index(_), _) => {
+                    return Err(EvalError::InvalidField("Cannot index into a non-list param"))
+                }
+                _ => unreachable!("filters can't contain wildcards"),
+            };
+        }
+
+        Ok(param)
+    }
+}
+
+impl ParamStep {
+    /// Whether two steps select the same child, regardless of where in the struct it is stored
+    fn same_key(&self, other: &ParamStep) -> bool {
+        match (self, other) {
+            (ParamStep::Field(_, a), ParamStep::Field(_, b)) => a == b,
+            (ParamStep::Index(a), ParamStep::Index(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl ParamPath {
+    /// The path with each wildcard replaced by what it matched. Fields named in `path` use that
+    /// name, any others use their hash's label if one is known.
+    fn name(&self, path: &PrcPath) -> String {
+        let names: Vec<&str> = path
+            .0
+            .iter()
+            .filter_map(|component| match component {
+                PrcPathComponent::Field(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        let mut out = String::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            if i != 0 {
+                out.push('.');
+            }
+            match *step {
+                ParamStep::Field(_, hash) => {
+                    match names.iter().find(|&&name| prc::hash40::hash40(name) == hash) {
+                        Some(name) => out.push_str(name),
+                        None => match label_of(hash) {
+                            Some(label) => out.push_str(&label),
+                            None => out.push_str(&hash.to_string()),
+                        },
                     }
-                    (ParamKind::List(_), _) => {
-                        return Err(EvalError::InvalidAssignment("values", "lists"))
+                }
+                ParamStep::Index(index) => out.push_str(&index.to_string()),
+            }
+        }
+
+        out
+    }
+
+    fn join(&self, step: ParamStep, wildcard: bool) -> Self {
+        let mut path = self.clone();
+        if wildcard {
+            path.wildcards.push(step.clone());
+        }
+        path.steps.push(step);
+        path
+    }
+
+    // param paths are only created by resolving a `PrcPath` against the same struct, and
+    // assignment never changes the layout of structs or lists, so indexing can't fail
+    fn get<'a>(&self, root: &'a ParamStruct) -> &'a ParamKind {
+        let (first, rest) = self.steps.split_first().expect("param paths are never empty");
+        let mut param = match *first {
+            ParamStep::Field(index, _) => &root.0[index].1,
+            ParamStep::Index(_) => unreachable!("param paths always start with a field"),
+        };
+
+        for step in rest {
+            param = match (step, param) {
+                (&ParamStep::Field(index, _), ParamKind::Struct(param)) => &param.0[index].1,
+                (&ParamStep::Index(index), ParamKind::List(list)) => &list.0[index],
+                _ => unreachable!("param path does not match the layout of the struct"),
+            };
+        }
+
+        param
+    }
+
+    fn get_mut<'a>(&self, root: &'a mut ParamStruct) -> &'a mut ParamKind {
+        let (first, rest) = self.steps.split_first().expect("param paths are never empty");
+        let mut param = match *first {
+            ParamStep::Field(index, _) => &mut root.0[index].1,
+            ParamStep::Index(_) => unreachable!("param paths always start with a field"),
+        };
+
+        for step in rest {
+            param = match (step, param) {
+                (&ParamStep::Field(index, _), ParamKind::Struct(param)) => &mut param.0[index].1,
+                (&ParamStep::Index(index), ParamKind::List(list)) => &mut list.0[index],
+                _ => unreachable!("param path does not match the layout of the struct"),
+            };
+        }
+
+        param
+    }
+}
+
+/// Everything an expression can see while being evaluated for a single param
+struct Context<'a, 'r> {
+    root: &'a ParamStruct,
+    original: &'a ParamKind,
+    sets: &'a HashMap<String, Set>,
+    /// What each wildcard in the entry's path matched for the param being evaluated
+    wildcards: &'a [ParamStep],
+    rng: &'r mut dyn RngCore,
+    /// Which `chance` was taken at each level of nesting so far
+    chances: Vec<usize>,
+    draws: &'r mut Draws,
+}
+
+/// Which values each `unique` set has given out so far, shared by every param a path matches
+#[derive(Default)]
+struct Draws {
+    /// Sets declared with `set`, by name
+    named: HashMap<String, Vec<bool>>,
+    /// Sets written inside a `return`, by their number within the entry
+    inline: HashMap<usize, Vec<bool>>,
+}
+
+impl<'a, 'r> Context<'a, 'r> {
+    /// Look up the param a `ref(...)` points to. Wildcards in the reference are bound, in order,
+    /// to whatever the wildcards of the param being evaluated matched.
+    fn lookup_ref(&self, path: &PrcPath) -> Result<&'a ParamKind> {
+        let wildcards = self.wildcards;
+        let skipped = Cell::new(0);
+        let matches: Vec<ParamPath> = get_path_struct(self.root, path.as_ref(), ParamPath::default(), &skipped)
+            .map_err(|(err, _)| err)?
+            .into_iter()
+            .filter(|param| {
+                param
+                    .wildcards
+                    .iter()
+                    .zip(wildcards)
+                    .all(|(a, b)| a.same_key(b))
+            })
+            .collect();
+
+        match matches.as_slice() {
+            [param] => Ok(param.get(self.root)),
+            [] => Err(EvalError::InvalidRef(format!("`{}` did not match any param", path))),
+            _ => Err(EvalError::InvalidRef(format!(
+                "`{}` matched more than one param",
+                path
+            ))),
+        }
+    }
+}
+
+fn original_number(param: &ParamKind) -> Result<f64> {
+    Ok(match *param {
+        ParamKind::I8(i) => i as f64,
+        ParamKind::U8(i) => i as f64,
+        ParamKind::I16(i) => i as f64,
+        ParamKind::U16(i) => i as f64,
+        ParamKind::I32(i) => i as f64,
+        ParamKind::U32(i) => i as f64,
+        ParamKind::Float(f) => f as f64,
+        _ => return Err(EvalError::NonNumericOriginal(kind_name(param))),
+    })
+}
+
+/// The value of a param, so it can be assigned to another one
+fn param_value(param: &ParamKind) -> Result<Value> {
+    Ok(match *param {
+        ParamKind::Bool(b) => Value::Bool(b),
+        ParamKind::I8(i) => Value::Int(i as i64),
+        ParamKind::U8(i) => Value::Int(i as i64),
+        ParamKind::I16(i) => Value::Int(i as i64),
+        ParamKind::U16(i) => Value::Int(i as i64),
+        ParamKind::I32(i) => Value::Int(i as i64),
+        ParamKind::U32(i) => Value::Int(i as i64),
+        ParamKind::Float(f) => Value::Float(f as f64),
+        ParamKind::Hash(hash) => Value::Hash40(hash),
+        ParamKind::Str(ref s) => Value::String(s.clone()),
+        _ => return Err(EvalError::CannotShuffle(kind_name(param))),
+    })
+}
+
+/// Apply `op` to the numeric value of `original`, rounding the result back to an integer if the
+/// param is an integer type
+fn relative<F: FnOnce(f64) -> f64>(original: &ParamKind, op: F) -> Result<Value> {
+    Ok(number_for(original, op(original_number(original)?)))
+}
+
+/// A number to write to a param, rounded for int params. Params that aren't numbers get a float,
+/// which fails to assign with a plain type error.
+fn number_for(param: &ParamKind, x: f64) -> Value {
+    match param {
+        ParamKind::I8(_)
+        | ParamKind::U8(_)
+        | ParamKind::I16(_)
+        | ParamKind::U16(_)
+        | ParamKind::I32(_)
+        | ParamKind::U32(_) => Value::Int(x.round() as i64),
+        _ => Value::Float(x),
+    }
+}
+
+/// The name of a param's type, as used in error messages
+fn kind_name(param: &ParamKind) -> &'static str {
+    match param {
+        ParamKind::Bool(_) => "bool",
+        ParamKind::I8(_) => "i8",
+        ParamKind::U8(_) => "u8",
+        ParamKind::I16(_) => "i16",
+        ParamKind::U16(_) => "u16",
+        ParamKind::I32(_) => "i32",
+        ParamKind::U32(_) => "u32",
+        ParamKind::Float(_) => "float",
+        ParamKind::Hash(_) => "hash40",
+        ParamKind::Str(_) => "string",
+        ParamKind::List(_) => "list",
+        ParamKind::Struct(_) => "struct",
+    }
+}
+
+impl Value {
+    /// The name of the value's type, as used in error messages
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::Hash40(_) => "hash40",
+            Value::Original => "original",
+        }
+    }
+}
+
+fn assign(param: &mut ParamKind, val: Value) -> Result<()> {
+    match (param, val) {
+        (_, Value::Original) => {}
+        (ParamKind::Bool(b), Value::Bool(new)) => {
+            *b = new;
+        }
+        (ParamKind::I8(i), Value::Int(new)) => {
+            *i = new.try_into().map_err(|_| EvalError::IntTooBig(new, "i8"))?;
+        }
+        (ParamKind::U8(i), Value::Int(new)) => {
+            *i = new.try_into().map_err(|_| EvalError::IntTooBig(new, "u8"))?;
+        }
+        (ParamKind::I16(i), Value::Int(new)) => {
+            *i = new.try_into().map_err(|_| EvalError::IntTooBig(new, "i16"))?;
+        }
+        (ParamKind::U16(i), Value::Int(new)) => {
+            *i = new.try_into().map_err(|_| EvalError::IntTooBig(new, "u16"))?;
+        }
+        (ParamKind::I32(i), Value::Int(new)) => {
+            *i = new.try_into().map_err(|_| EvalError::IntTooBig(new, "i32"))?;
+        }
+        (ParamKind::U32(i), Value::Int(new)) => {
+            *i = new.try_into().map_err(|_| EvalError::IntTooBig(new, "u32"))?;
+        }
+        (ParamKind::Float(f), Value::Int(new)) => {
+            *f = new as f32;
+        }
+        (ParamKind::Float(f), Value::Float(new)) => {
+            *f = new as f32;
+        }
+        (ParamKind::Hash(h), Value::Int(new)) => {
+            *h = Hash40(new as u64);
+        }
+        (ParamKind::Hash(h), Value::String(ref s)) => {
+            *h = prc::hash40::hash40(&s);
+        }
+        (ParamKind::Hash(h), Value::Hash40(new)) => {
+            *h = new;
+        }
+        (ParamKind::Str(s), Value::String(ref new)) => {
+            *s = new.clone();
+        }
+        // name both types from the values themselves so the message can't be mislabeled
+        (param, val) => {
+            return Err(EvalError::InvalidAssignment(
+                val.type_name(),
+                kind_name(param),
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+impl ParamStep {
+    fn hash(&self, hasher: SeedHasher) -> SeedHasher {
+        match *self {
+            ParamStep::Field(_, hash) => hasher.u64(0).u64(hash.0),
+            ParamStep::Index(index) => hasher.u64(1).u64(index as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplyMode {
+    /// Stop at the first error, leaving the param file how it was before applying
+    AllOrNothing,
+    /// Skip any params that fail, applying everything else
+    BestEffort,
+}
+
+/// A record of everything done while applying entries to a param file
+#[derive(Default)]
+struct Journal<'p> {
+    /// The path of the prc file in game
+    prc_path: &'p str,
+    /// The value of every param before it was written to, in the order they were written
+    undo: Vec<(ParamPath, ParamKind)>,
+    /// Whether to fill `report`
+    reporting: bool,
+    report: Report,
+    errors: Vec<EvalDiagnostic>,
+}
+
+impl Journal<'_> {
+    fn error(&mut self, param: String, error: EvalError) {
+        self.errors.push(EvalDiagnostic {
+            error,
+            prc_path: self.prc_path.to_owned(),
+            param,
+        });
+    }
+
+    /// Write `val` to a param, keeping the old value so it can be reported or undone
+    fn write(
+        &mut self,
+        path: &PrcPath,
+        param: &ParamPath,
+        file: &mut ParamStruct,
+        val: Value,
+        chances: Vec<usize>,
+    ) -> Result<()> {
+        let old = param.get(file).clone();
+        assign(param.get_mut(file), val)?;
+
+        if self.reporting {
+            self.report.changes.push(Change {
+                prc_path: self.prc_path.to_owned(),
+                param: param.name(path),
+                old: old.clone(),
+                new: param.get(file).clone(),
+                chances,
+            });
+        }
+        self.undo.push((param.clone(), old));
+
+        Ok(())
+    }
+
+    fn rollback(&mut self, file: &mut ParamStruct) {
+        for (param, old) in self.undo.drain(..).rev() {
+            *param.get_mut(file) = old;
+        }
+        self.report = Report::default();
+    }
+
+    fn into_result(self) -> std::result::Result<Report, Errors> {
+        if self.errors.is_empty() {
+            Ok(self.report)
+        } else {
+            Err(Diagnostics(self.errors))
+        }
+    }
+}
+
+/// Apply each entry in order, collecting every error. In `AllOrNothing` mode, any error rolls
+/// back every entry once they've all been evaluated.
+fn apply_entries<'a, 'p, I: IntoIterator<Item = &'a RandlEntry>>(
+    entries: I,
+    prc_path: &'p str,
+    file: &mut ParamStruct,
+    sets: &HashMap<String, Set>,
+    seed: u64,
+    mode: ApplyMode,
+    reporting: bool,
+) -> Journal<'p> {
+    let mut journal = Journal {
+        prc_path,
+        reporting,
+        ..Journal::default()
+    };
+
+    for entry in entries {
+        entry.apply_inner(file, sets, seed, &mut journal);
+    }
+
+    if let ApplyMode::AllOrNothing = mode {
+        if !journal.errors.is_empty() {
+            journal.rollback(file);
+        }
+    }
+
+    journal
+}
+
+impl RandlEntry {
+    /// Apply the entry to a param file using a non-reproducible source of randomness
+    pub fn apply(
+        &self,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+    ) -> std::result::Result<(), Errors> {
+        self.apply_with_rng(file, sets, &mut rand::thread_rng())
+    }
+
+    /// Apply the entry to a param file, using `rng` to pick a seed
+    pub fn apply_with_rng<R: RngCore>(
+        &self,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+        rng: &mut R,
+    ) -> std::result::Result<(), Errors> {
+        self.apply_seeded(&self.prc_name, file, sets, rng.next_u64())
+    }
+
+    /// Apply the entry to the param file at `prc_path`. The same seed will always produce the
+    /// same output. If any param fails to evaluate, the file is left unchanged and every error is
+    /// returned.
+    ///
+    /// Every param gets its own stream of random numbers derived from the seed, the prc path,
+    /// the entry's path and what its wildcards matched. Adding an entry or loading files in a
+    /// different order won't change the values picked for any other param.
+    pub fn apply_seeded(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+        seed: u64,
+    ) -> std::result::Result<(), Errors> {
+        let mode = ApplyMode::AllOrNothing;
+        apply_entries(Some(self), prc_path, file, sets, seed, mode, false)
+            .into_result()
+            .map(drop)
+    }
+
+    /// Same as `apply_seeded`, but also returns every param that was evaluated, along with its
+    /// old and new value
+    pub fn apply_with_report(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+        seed: u64,
+    ) -> std::result::Result<Report, Errors> {
+        let mode = ApplyMode::AllOrNothing;
+        apply_entries(Some(self), prc_path, file, sets, seed, mode, true).into_result()
+    }
+
+    /// Same as `apply_with_report`, but any params that fail are skipped rather than stopping.
+    /// Returns everything that was applied along with every error hit.
+    pub fn apply_best_effort(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+        seed: u64,
+    ) -> (Report, Vec<EvalDiagnostic>) {
+        let mode = ApplyMode::BestEffort;
+        let journal = apply_entries(Some(self), prc_path, file, sets, seed, mode, true);
+
+        (journal.report, journal.errors)
+    }
+
+    fn apply_inner(
+        &self,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+        seed: u64,
+        journal: &mut Journal,
+    ) {
+        let seed = SeedHasher::new(seed).str(journal.prc_path).str(&self.prc_name);
+        for (i, field) in self.prc_fields.iter().enumerate() {
+            let skipped = Cell::new(0);
+            let to_edit = get_path_struct(file, field.path.as_ref(), ParamPath::default(), &skipped);
+            let to_edit = match to_edit {
+                Ok(to_edit) => to_edit,
+                Err((error, at)) => {
+                    // name the param as far as it could be resolved
+                    let param = if at.steps.is_empty() {
+                        field.path.to_string()
+                    } else {
+                        at.name(&field.path)
+                    };
+                    journal.error(param, error);
+                    continue;
+                }
+            };
+
+            if journal.reporting && skipped.get() != 0 {
+                journal.report.skipped.push(Skipped {
+                    prc_path: journal.prc_path.to_owned(),
+                    path: field.path.to_string(),
+                    count: skipped.get(),
+                });
+            }
+
+            let seed = match &field.group {
+                // every entry in a group rolls from the same stream
+                Some(group) => seed.clone().str("group").str(&group.name),
+                None => {
+                    // entries with the same path still need different rolls
+                    let path = field.path.to_string();
+                    let occurrence = self.prc_fields[..i]
+                        .iter()
+                        .filter(|other| other.group.is_none() && other.path.to_string() == path)
+                        .count();
+                    seed.clone().str(&path).u64(occurrence as u64)
+                }
+            };
+            let group_mode = field.group.as_ref().map(|group| group.mode).unwrap_or(Mode::Each);
+
+            if let Expr::Shuffle = field.expr {
+                field.shuffle(&to_edit, file, seed, journal);
+                continue;
+            }
+
+            // evaluate and write each param before moving on, so `ref(...)` sees the params
+            // that have already been randomized
+            let mut draws = Draws::default();
+            let mut once: Option<(Value, Vec<usize>)> = None;
+            for param in to_edit {
+                let evaluated = match &once {
+                    Some(evaluated) => Ok(evaluated.clone()),
+                    None => {
+                        let seed = match (field.mode, group_mode) {
+                            (Mode::Each, Mode::Each) => param
+                                .wildcards
+                                .iter()
+                                .fold(seed.clone(), |seed, step| step.hash(seed)),
+                            _ => seed.clone(),
+                        };
+                        field.eval_param(&param, file, sets, seed, &mut draws)
                     }
-                    (ParamKind::Struct(_), _) => {
-                        return Err(EvalError::InvalidAssignment("values", "structs"))
+                };
+
+                let result = evaluated.and_then(|(val, chances)| {
+                    if field.mode == Mode::Once {
+                        once = Some((val.clone(), chances.clone()));
                     }
-                    // look, sometimes I write bad code too.
-                    // at minimum give me credit for automating this externally
-                    (ParamKind::Bool(_), Value::Int(_)) => return e("int", "bool"),
-                    (ParamKind::Bool(_), Value::Float(_)) => return e("float", "bool"),
-                    (ParamKind::Bool(_), Value::String(_)) => return e("string", "bool"),
-                    (ParamKind::Bool(_), Value::Hash40(_)) => return e("hash40", "bool"),
-                    (ParamKind::I8(_), Value::Float(_)) => return e("float", "int"),
-                    (ParamKind::I8(_), Value::String(_)) => return e("string", "int"),
-                    (ParamKind::I8(_), Value::Bool(_)) => return e("bool", "int"),
-                    (ParamKind::I8(_), Value::Hash40(_)) => return e("hash40", "int"),
-                    (ParamKind::U8(_), Value::Float(_)) => return e("float", "int"),
-                    (ParamKind::U8(_), Value::String(_)) => return e("string", "int"),
-                    (ParamKind::U8(_), Value::Bool(_)) => return e("bool", "int"),
-                    (ParamKind::U8(_), Value::Hash40(_)) => return e("hash40", "int"),
-                    (ParamKind::I16(_), Value::Float(_)) => return e("float", "int"),
-                    (ParamKind::I16(_), Value::String(_)) => return e("string", "int"),
-                    (ParamKind::I16(_), Value::Bool(_)) => return e("bool", "int"),
-                    (ParamKind::I16(_), Value::Hash40(_)) => return e("hash40", "int"),
-                    (ParamKind::U16(_), Value::Float(_)) => return e("float", "int"),
-                    (ParamKind::U16(_), Value::String(_)) => return e("string", "int"),
-                    (ParamKind::U16(_), Value::Bool(_)) => return e("float", "int"),
-                    (ParamKind::U16(_), Value::Hash40(_)) => return e("hash40", "int"),
-                    (ParamKind::I32(_), Value::Float(_)) => return e("float", "int"),
-                    (ParamKind::I32(_), Value::String(_)) => return e("string", "int"),
-                    (ParamKind::I32(_), Value::Bool(_)) => return e("bool", "int"),
-                    (ParamKind::I32(_), Value::Hash40(_)) => return e("hash40", "int"),
-                    (ParamKind::U32(_), Value::Float(_)) => return e("float", "int"),
-                    (ParamKind::U32(_), Value::String(_)) => return e("string", "int"),
-                    (ParamKind::U32(_), Value::Bool(_)) => return e("bool", "int"),
-                    (ParamKind::U32(_), Value::Hash40(_)) => return e("hash40", "int"),
-                    (ParamKind::Float(_), Value::String(_)) => return e("string", "float"),
-                    (ParamKind::Float(_), Value::Bool(_)) => return e("bool", "float"),
-                    (ParamKind::Float(_), Value::Hash40(_)) => return e("hash40", "float"),
-                    (ParamKind::Hash(_), Value::Float(_)) => return e("float", "hash40"),
-                    (ParamKind::Hash(_), Value::Bool(_)) => return e("string", "float"),
-                    (ParamKind::Str(_), Value::Int(_)) => return e("int", "string"),
-                    (ParamKind::Str(_), Value::Float(_)) => return e("float", "string"),
-                    (ParamKind::Str(_), Value::Bool(_)) => return e("bool", "string"),
-                    (ParamKind::Str(_), Value::Hash40(_)) => return e("hash40", "string"),
+                    journal.write(&field.path, &param, file, val, chances)
+                });
+                if let Err(error) = result {
+                    journal.error(param.name(&field.path), error);
                 }
             }
         }
+    }
+}
 
-        Ok(())
+impl PrcEntry {
+    /// Pick a new value for a param, along with the chances taken to get it
+    fn eval_param(
+        &self,
+        param: &ParamPath,
+        file: &mut ParamStruct,
+        sets: &HashMap<String, Set>,
+        seed: SeedHasher,
+        draws: &mut Draws,
+    ) -> Result<(Value, Vec<usize>)> {
+        let mut ctx = Context {
+            root: file,
+            original: param.get(file),
+            sets,
+            wildcards: &param.wildcards,
+            rng: &mut RandlRng::new(seed.finish()),
+            chances: Vec::new(),
+            draws,
+        };
+        let val = self.expr.eval(&mut ctx)?;
+
+        Ok((val, ctx.chances))
+    }
+
+    /// Redistribute the current values of every matched param in a random order
+    fn shuffle(
+        &self,
+        to_edit: &[ParamPath],
+        file: &mut ParamStruct,
+        seed: SeedHasher,
+        journal: &mut Journal,
+    ) {
+        let mut values = Vec::with_capacity(to_edit.len());
+        for param in to_edit {
+            match param_value(param.get(file)) {
+                Ok(value) => values.push(value),
+                Err(error) => return journal.error(param.name(&self.path), error),
+            }
+        }
+
+        // fisher-yates
+        let rng = &mut RandlRng::new(seed.finish());
+        for i in (1..values.len()).rev() {
+            values.swap(i, gen_index(rng, i + 1));
+        }
+
+        for (param, value) in to_edit.iter().zip(values) {
+            if let Err(error) = journal.write(&self.path, param, file, value, Vec::new()) {
+                journal.error(param.name(&self.path), error);
+            }
+        }
+    }
+}
+
+impl RandlFile {
+    fn matching_entries<'a>(&'a self, prc_path: &'a str) -> impl Iterator<Item = &'a RandlEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.matches_path(prc_path, &self.sets))
+    }
+
+    /// Apply every entry matching `prc_path` to the given param file, in the order they were
+    /// declared, using `rng` to pick a seed
+    pub fn apply_with_rng<R: RngCore>(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        rng: &mut R,
+    ) -> std::result::Result<(), Errors> {
+        self.apply_seeded(prc_path, file, rng.next_u64())
+    }
+
+    /// Apply every entry matching `prc_path` to the given param file, in the order they were
+    /// declared. The same seed will always produce the same output. If any param fails to
+    /// evaluate, the file is left unchanged and every error is returned.
+    pub fn apply_seeded(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        seed: u64,
+    ) -> std::result::Result<(), Errors> {
+        let entries = self.matching_entries(prc_path);
+        let mode = ApplyMode::AllOrNothing;
+        apply_entries(entries, prc_path, file, &self.sets, seed, mode, false)
+            .into_result()
+            .map(drop)
+    }
+
+    /// Same as `apply_seeded`, but also returns every param that was evaluated, along with its
+    /// old and new value
+    pub fn apply_with_report(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        seed: u64,
+    ) -> std::result::Result<Report, Errors> {
+        let entries = self.matching_entries(prc_path);
+        let mode = ApplyMode::AllOrNothing;
+        apply_entries(entries, prc_path, file, &self.sets, seed, mode, true).into_result()
+    }
+
+    /// Same as `apply_with_report`, but any params that fail are skipped rather than stopping.
+    /// Returns everything that was applied along with every error hit.
+    pub fn apply_best_effort(
+        &self,
+        prc_path: &str,
+        file: &mut ParamStruct,
+        seed: u64,
+    ) -> (Report, Vec<EvalDiagnostic>) {
+        let entries = self.matching_entries(prc_path);
+        let mode = ApplyMode::BestEffort;
+        let journal = apply_entries(entries, prc_path, file, &self.sets, seed, mode, true);
+
+        (journal.report, journal.errors)
     }
 }
 
 impl Expr {
-    fn eval(&self, sets: &HashMap<String, Set>) -> Result<Value> {
+    fn eval(&self, ctx: &mut Context) -> Result<Value> {
         match self {
             Expr::Random(chances) => {
-                if chances.len() == 1 {
-                    chances[0].expr.eval(sets)
-                } else {
-                    let mut percentile = rand::thread_rng().gen::<f64>() * 100.0;
+                let total: f64 = chances.iter().map(|chance| chance.percent).sum();
+                let full = total >= 100.0 - CHANCE_EPSILON;
+                if chances.len() == 1 && full {
+                    ctx.chances.push(0);
+                    return chances[0].expr.eval(ctx);
+                }
 
-                    let (last, chances) = chances.split_last().unwrap();
-                    for chance in chances {
-                        percentile -= chance.percent;
-                        if percentile < 0.0 {
-                            return chance.expr.eval(sets)
-                        }
+                let mut percentile = gen_float(ctx.rng) * 100.0;
+                for (i, chance) in chances.iter().enumerate() {
+                    percentile -= chance.percent;
+                    if percentile < 0.0 {
+                        ctx.chances.push(i);
+                        return chance.expr.eval(ctx);
                     }
+                }
 
-                    last.expr.eval(sets)
+                if full {
+                    // float error can leave a sliver past the last chance
+                    ctx.chances.push(chances.len() - 1);
+                    chances[chances.len() - 1].expr.eval(ctx)
+                } else {
+                    // percents adding up to less than 100 leave the rest to the original value
+                    Ok(Value::Original)
                 }
             }
             Expr::Return(ret) => match ret {
                 Return::Constant(c) => Ok(c.clone()),
-                Return::Range(range) => match range {
-                    &Range::Int(from, to) => Ok(Value::Int(rand::thread_rng().gen_range(from..to))),
-                    &Range::Float(from, to) => {
-                        Ok(Value::Float(rand::thread_rng().gen_range(from..to)))
+                Return::Range(range) => Ok(match *range {
+                    Range::Int { from, step, count } => {
+                        Value::Int(from + gen_int_range(ctx.rng, 0, count) * step)
+                    }
+                    Range::Float(from, to) => Value::Float(gen_float_range(ctx.rng, from, to)),
+                    Range::FloatSteps { from, step, count } => {
+                        Value::Float(from + gen_int_range(ctx.rng, 0, count) as f64 * step)
                     }
-                },
-                Return::Set(set) => {
-                    let set = sets.get(set).ok_or_else(|| EvalError::InvalidSet(set.clone()))?;
-                    set.eval()
+                }),
+                &Return::Multiply(factor) => relative(ctx.original, |x| x * factor),
+                &Return::Add(offset) => relative(ctx.original, |x| x + offset),
+                &Return::Scale(from, to) => {
+                    let factor = gen_float_range(ctx.rng, from, to);
+                    relative(ctx.original, |x| x * factor)
+                }
+                // `original` is only required to be a number where these read it
+                Return::Formula(formula) => Ok(number_for(ctx.original, formula.eval(ctx)?)),
+                Return::Distribution(dist) => Ok(number_for(ctx.original, dist.sample(ctx)?)),
+                Return::Set(name, draw) => {
+                    let set = ctx.sets.get(name);
+                    let set = set.ok_or_else(|| EvalError::InvalidSet(name.clone()))?;
+                    let drawn = ctx.draws.named.entry(name.clone()).or_default();
+                    set.pick(ctx.rng, *draw, drawn)
+                }
+                Return::AnonymousSet(set, draw, id) => {
+                    let drawn = ctx.draws.inline.entry(*id).or_default();
+                    set.pick(ctx.rng, *draw, drawn)
                 }
-                Return::AnonymousSet(set) => set.eval(),
             },
+            Expr::When(whens, otherwise) => {
+                for when in whens {
+                    let mut taken = true;
+                    for condition in when.conditions.iter() {
+                        if !condition.test(ctx.original)? {
+                            taken = false;
+                            break;
+                        }
+                    }
+                    if taken {
+                        return when.expr.eval(ctx);
+                    }
+                }
+
+                match otherwise {
+                    Some(expr) => expr.eval(ctx),
+                    None => Ok(Value::Original),
+                }
+            }
             Expr::Original => Ok(Value::Original),
+            Expr::Shuffle => unreachable!("shuffles are applied to every match at once"),
         }
     }
 }
 
+impl Condition {
+    fn test(&self, original: &ParamKind) -> Result<bool> {
+        let equal = match (original, &self.value) {
+            (ParamKind::Bool(a), Value::Bool(b)) => a == b,
+            (ParamKind::Str(a), Value::String(b)) => a == b,
+            (ParamKind::Hash(a), Value::Hash40(b)) => a == b,
+            (_, Value::Int(_)) | (_, Value::Float(_)) => return self.compare(original),
+            (_, value) => {
+                return Err(EvalError::InvalidComparison(kind_name(original), value.type_name()))
+            }
+        };
+
+        Ok(match self.op {
+            CmpOp::Ne => !equal,
+            _ => equal,
+        })
+    }
+
+    fn compare(&self, original: &ParamKind) -> Result<bool> {
+        let value = match self.value {
+            Value::Int(int) => int as f64,
+            Value::Float(f) => f,
+            _ => unreachable!("only numbers are ordered"),
+        };
+        let (original, value) = match *original {
+            // compare at the param's own precision, so `original=1.1` matches a float set to 1.1
+            ParamKind::Float(f) => (f as f64, value as f32 as f64),
+            _ => match original_number(original) {
+                Ok(x) => (x, value),
+                Err(_) => {
+                    let value = self.value.type_name();
+                    return Err(EvalError::InvalidComparison(kind_name(original), value));
+                }
+            },
+        };
+
+        Ok(match self.op {
+            CmpOp::Eq => original == value,
+            CmpOp::Ne => original != value,
+            CmpOp::Lt => original < value,
+            CmpOp::Le => original <= value,
+            CmpOp::Gt => original > value,
+            CmpOp::Ge => original >= value,
+        })
+    }
+}
+
+impl Distribution {
+    fn sample(&self, ctx: &mut Context) -> Result<f64> {
+        let rng = &mut *ctx.rng;
+        let x = match self.kind {
+            DistKind::Normal { mean, stddev } => mean + stddev * gen_normal(rng),
+            DistKind::NormalAround { sd_percent } => {
+                let original = original_number(ctx.original)?;
+                original + original.abs() * sd_percent / 100.0 * gen_normal(rng)
+            }
+            DistKind::Triangular { from, peak, to } => {
+                // inverse of the cdf
+                let u = gen_float(rng);
+                let split = (peak - from) / (to - from);
+                if u < split {
+                    from + (u * (to - from) * (peak - from)).sqrt()
+                } else {
+                    to - ((1.0 - u) * (to - from) * (to - peak)).sqrt()
+                }
+            }
+            DistKind::LogUniform { from, to } => {
+                libm::exp(gen_float_range(rng, libm::log(from), libm::log(to)))
+            }
+            DistKind::Exponential { mean } => -mean * libm::log(1.0 - gen_float(rng)),
+            DistKind::Beta { alpha, beta, from, to } => {
+                let x = gen_gamma(rng, alpha);
+                let y = gen_gamma(rng, beta);
+                from + (to - from) * (x / (x + y))
+            }
+        };
+
+        let x = self.min.map_or(x, |min| x.max(min));
+        Ok(self.max.map_or(x, |max| x.min(max)))
+    }
+}
+
 impl Set {
-    fn eval(&self) -> Result<Value> {
-        let len = self.0.len();
+    fn eval(&self, rng: &mut dyn RngCore) -> Result<Value> {
+        let first = self.0[0].weight;
+        let index = if self.0.iter().all(|choice| choice.weight == first) {
+            // picking uniformly keeps seeds giving the same results as before sets had weights
+            gen_index(rng, self.0.len())
+        } else {
+            let weights: Vec<f64> = self.0.iter().map(|choice| choice.weight).collect();
+            gen_weighted_index(rng, &weights)
+        };
+
+        Ok(self.0[index].value.clone())
+    }
+
+    /// Pick a value, where `drawn` is what's been given out by this set so far
+    fn pick(&self, rng: &mut dyn RngCore, draw: Draw, drawn: &mut Vec<bool>) -> Result<Value> {
+        let refill = match draw {
+            Draw::Independent => return self.eval(rng),
+            Draw::Unique => false,
+            Draw::UniqueRefill => true,
+        };
+
+        self.draw(rng, drawn, refill)
+    }
+
+    /// Pick a value that hasn't been given to any other param matched by the same path
+    fn draw(&self, rng: &mut dyn RngCore, drawn: &mut Vec<bool>, refill: bool) -> Result<Value> {
+        drawn.resize(self.0.len(), false);
+
+        let available = |drawn: &[bool]| {
+            self.0
+                .iter()
+                .zip(drawn)
+                .any(|(choice, &drawn)| !drawn && choice.weight > 0.0)
+        };
+        if !available(drawn) {
+            if refill {
+                drawn.iter_mut().for_each(|drawn| *drawn = false);
+            } else {
+                return Err(EvalError::SetExhausted);
+            }
+        }
+
+        let weights: Vec<f64> = self
+            .0
+            .iter()
+            .zip(drawn.iter())
+            .map(|(choice, &drawn)| if drawn { 0.0 } else { choice.weight })
+            .collect();
+        let index = gen_weighted_index(rng, &weights);
+        drawn[index] = true;
+
+        Ok(self.0[index].value.clone())
+    }
+}
+
+impl Formula {
+    fn eval(&self, ctx: &mut Context) -> Result<f64> {
+        Ok(match self {
+            &Formula::Number(num) => num,
+            Formula::Original => original_number(ctx.original)?,
+            Formula::Ref(path) => original_number(ctx.lookup_ref(path)?)?,
+            Formula::Neg(inner) => -inner.eval(ctx)?,
+            Formula::BinOp(op, lhs, rhs) => {
+                let lhs = lhs.eval(ctx)?;
+                let rhs = rhs.eval(ctx)?;
+                match op {
+                    BinOp::Add => lhs + rhs,
+                    BinOp::Sub => lhs - rhs,
+                    BinOp::Mul => lhs * rhs,
+                    BinOp::Div | BinOp::Rem if rhs == 0.0 => return Err(EvalError::DivideByZero),
+                    BinOp::Div => lhs / rhs,
+                    BinOp::Rem => lhs % rhs,
+                }
+            }
+            Formula::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(ctx))
+                    .collect::<Result<Vec<f64>>>()?;
+
+                match function {
+                    // the bounds often come from the original value, which can make them equal
+                    // or reversed at runtime, so those are sampled rather than rejected
+                    Function::Rand => {
+                        let (from, to) = if args[0] <= args[1] {
+                            (args[0], args[1])
+                        } else {
+                            (args[1], args[0])
+                        };
+                        if from < to {
+                            gen_float_range(ctx.rng, from, to)
+                        } else if from == to {
+                            from
+                        } else {
+                            return Err(EvalError::EmptyRange(args[0], args[1]));
+                        }
+                    }
+                    Function::RandInt => {
+                        let (a, b) = (args[0].round() as i64, args[1].round() as i64);
+                        let (from, to) = (a.min(b), a.max(b));
+                        // `to` can be `i64::MAX`, which gen_int_range handles wrapping to
+                        gen_int_range(ctx.rng, from, to.wrapping_add(1)) as f64
+                    }
+                    Function::Min => args.into_iter().fold(f64::INFINITY, f64::min),
+                    Function::Max => args.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                    Function::Clamp => args[0].max(args[1]).min(args[2]),
+                    Function::Round => args[0].round(),
+                    Function::Floor => args[0].floor(),
+                    Function::Ceil => args[0].ceil(),
+                    Function::Abs => args[0].abs(),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(selections: &[Selection], len: usize) -> Vec<usize> {
+        Selection::resolve_all(selections, len).unwrap()
+    }
+
+    #[test]
+    fn resolve_indices() {
+        assert_eq!(resolve(&[Selection::Index(2)], 5), [2]);
+        assert_eq!(resolve(&[Selection::Index(-1)], 5), [4]);
+        assert_eq!(resolve(&[Selection::Index(-5)], 5), [0]);
+        // sorted and deduplicated
+        let selections = [Selection::Index(3), Selection::Index(0), Selection::Index(-2)];
+        assert_eq!(resolve(&selections, 5), [0, 3]);
+    }
+
+    #[test]
+    fn resolve_ranges() {
+        assert_eq!(resolve(&[Selection::Range(Some(1), Some(3))], 5), [1, 2]);
+        assert_eq!(resolve(&[Selection::Range(None, Some(2))], 5), [0, 1]);
+        assert_eq!(resolve(&[Selection::Range(Some(-2), None)], 5), [3, 4]);
+        assert_eq!(resolve(&[Selection::Range(None, None)], 3), [0, 1, 2]);
+        assert_eq!(resolve(&[Selection::Range(Some(-3), Some(-1))], 5), [2, 3]);
+        // ranges are cut short at the ends of the list
+        assert_eq!(resolve(&[Selection::Range(None, Some(3))], 2), [0, 1]);
+        assert_eq!(resolve(&[Selection::Range(Some(-10), Some(1))], 3), [0]);
+        assert!(resolve(&[Selection::Range(Some(4), Some(9))], 3).is_empty());
+        // mixed signs can end up empty depending on the length
+        assert!(resolve(&[Selection::Range(Some(-1), Some(2))], 5).is_empty());
+        let selections = [Selection::Range(Some(0), Some(4)), Selection::Index(1)];
+        assert_eq!(resolve(&selections, 5), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn resolve_out_of_bounds() {
+        assert!(matches!(
+            Selection::resolve_all(&[Selection::Index(5)], 5),
+            Err(EvalError::SelectionOutOfBounds(5, 5))
+        ));
+        assert!(matches!(
+            Selection::resolve_all(&[Selection::Index(-6)], 5),
+            Err(EvalError::SelectionOutOfBounds(-6, 5))
+        ));
+        assert!(Selection::resolve_all(&[Selection::Index(0)], 0).is_err());
+    }
+
+    const PRC_PATH: &str = "fighter/mario/param/vl.prc";
+
+    fn params(fields: Vec<(&str, ParamKind)>) -> ParamStruct {
+        ParamStruct(
+            fields
+                .into_iter()
+                .map(|(name, param)| (prc::hash40::hash40(name), param))
+                .collect(),
+        )
+    }
+
+    /// A small struct shaped like a fighter's params
+    fn sample() -> ParamStruct {
+        let hitbox = |start, end, damage, kind| {
+            ParamKind::Struct(params(vec![
+                ("start_frame", ParamKind::U8(start)),
+                ("end_frame", ParamKind::U8(end)),
+                ("damage", ParamKind::Float(damage)),
+                ("kind", ParamKind::Hash(prc::hash40::hash40(kind))),
+            ]))
+        };
+        params(vec![
+            (
+                "attack_data",
+                ParamKind::List(prc::ParamList(vec![
+                    hitbox(3, 5, 8.0, "collision_attr_normal"),
+                    hitbox(10, 14, 12.0, "collision_attr_normal"),
+                    hitbox(20, 25, 4.5, "collision_attr_fire"),
+                    hitbox(30, 31, 0.0, "collision_attr_normal"),
+                ])),
+            ),
+            ("walk_speed", ParamKind::Float(1.5)),
+            ("run_speed", ParamKind::Float(2.0)),
+            ("jump_count", ParamKind::I32(2)),
+        ])
+    }
+
+    /// Parse `entries`, written as the body of a `file` node for the sample, after `sets`
+    fn parse_with_sets(sets: &str, entries: &str) -> RandlFile {
+        let src = format!("{}\nfile {:?} {{\n{}\n}}", sets, PRC_PATH, entries);
+        RandlFile::from_str(&src).unwrap()
+    }
+
+    fn parse(entries: &str) -> RandlFile {
+        parse_with_sets("", entries)
+    }
+
+    fn apply_with_sets(
+        sets: &str,
+        entries: &str,
+        seed: u64,
+    ) -> std::result::Result<ParamStruct, Errors> {
+        let mut file = sample();
+        parse_with_sets(sets, entries).apply_seeded(PRC_PATH, &mut file, seed).map(|_| file)
+    }
+
+    /// Apply `entries`, written as the body of a `file` node, to the sample
+    fn apply(entries: &str, seed: u64) -> std::result::Result<ParamStruct, Errors> {
+        apply_with_sets("", entries, seed)
+    }
+
+    fn field<'a>(file: &'a ParamStruct, name: &str) -> &'a ParamKind {
+        let hash = prc::hash40::hash40(name);
+        &file.0.iter().find(|(field, _)| *field == hash).unwrap().1
+    }
+
+    fn float(file: &ParamStruct, name: &str) -> f32 {
+        match field(file, name) {
+            ParamKind::Float(x) => *x,
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    /// `name` in each hitbox of `attack_data`
+    fn hitbox_fields<'a>(file: &'a ParamStruct, name: &str) -> Vec<&'a ParamKind> {
+        let list = match field(file, "attack_data") {
+            ParamKind::List(list) => list,
+            other => panic!("attack_data is {:?}", other),
+        };
+        list.0
+            .iter()
+            .map(|hitbox| match hitbox {
+                ParamKind::Struct(hitbox) => field(hitbox, name),
+                other => panic!("hitbox is {:?}", other),
+            })
+            .collect()
+    }
+
+    /// `name` in each hitbox of `attack_data`, as a float
+    fn hitboxes(file: &ParamStruct, name: &str) -> Vec<f32> {
+        hitbox_fields(file, name)
+            .into_iter()
+            .map(|param| match param {
+                ParamKind::Float(x) => *x,
+                ParamKind::U8(x) => *x as f32,
+                other => panic!("{} is {:?}", name, other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rand_bounds_from_original() {
+        for seed in 0..20 {
+            // equal bounds for the 0 damage hitbox, reversed ones for `-original`
+            let file = apply(
+                r#"
+                attack_data.*.damage { return expr="rand(original * 0.9, original * 1.1)" }
+                walk_speed { return expr="rand(original, -original)" }
+                jump_count { return expr="randint(original, -original)" }
+                "#,
+                seed,
+            )
+            .unwrap();
+
+            let damage = hitboxes(&file, "damage");
+            for (&damage, &original) in damage.iter().zip(&[8.0, 12.0, 4.5]) {
+                assert!(original * 0.9 <= damage && damage <= original * 1.1, "{}", damage);
+            }
+            assert_eq!(damage[3], 0.0);
+            assert!((-1.5..=1.5).contains(&float(&file, "walk_speed")));
+            match field(&file, "jump_count") {
+                ParamKind::I32(count) => assert!((-2..=2).contains(count), "{}", count),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn file_matches_entry_by_entry() {
+        // how the plugin applies a file, one matching entry at a time in the order they're written
+        let randl = RandlFile::from_str(&format!(
+            r#"
+            set "fighters" {{
+                value "mario"
+                value "luigi"
+            }}
+            file "fighter/{{fighters}}/param/vl.prc" {{
+                attack_data.*.damage {{ return multiply=2 }}
+                walk_speed {{ return expr="rand(1, 2)" }}
+            }}
+            file "fighter/kirby/param/vl.prc" {{
+                walk_speed {{ return 10.0 }}
+            }}
+            file {:?} {{
+                attack_data.*.damage {{ return expr="original + rand(0, 1)" }}
+                run_speed {{ return from=1.0 to=3.0 }}
+            }}
+            "#,
+            PRC_PATH,
+        ))
+        .unwrap();
+
+        for seed in 0..10 {
+            let mut one_at_a_time = sample();
+            for entry in &randl.entries {
+                if entry.matches_path(PRC_PATH, &randl.sets) {
+                    entry.apply_seeded(PRC_PATH, &mut one_at_a_time, &randl.sets, seed).unwrap();
+                }
+            }
+
+            let mut all_at_once = sample();
+            randl.apply_with_report(PRC_PATH, &mut all_at_once, seed).unwrap();
+            assert_eq!(format!("{:?}", one_at_a_time), format!("{:?}", all_at_once));
+            // both entries were applied, doubling before adding
+            let damage = hitboxes(&all_at_once, "damage");
+            for (&damage, &original) in damage.iter().zip(&[8.0, 12.0, 4.5, 0.0]) {
+                assert!(original * 2.0 <= damage && damage < original * 2.0 + 1.0, "{}", damage);
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_same_output() {
+        let entries = r#"
+            attack_data.*.damage { return expr="original * rand(0.5, 1.5)" }
+            attack_data.*.start_frame { return from=1 to=30 }
+            walk_speed { return "scale" from=0.8 to=1.2 }
+        "#;
+        let debug = |seed| format!("{:?}", apply(entries, seed).unwrap());
+
+        assert_eq!(debug(1234), debug(1234));
+        assert_ne!(debug(1234), debug(1235));
+    }
+
+    #[test]
+    fn repeated_template_values_apply_once() {
+        let randl = RandlFile::from_str(
+            r#"
+            set "fighters" {
+                value "mario"
+                value "luigi"
+                value "mario"
+            }
+            file "fighter/{fighters}/param/vl.prc" {
+                walk_speed { return multiply=2 }
+            }
+            "#,
+        )
+        .unwrap();
+
+        let entry = &randl.entries[0];
+        assert_eq!(
+            entry.prc_paths(&randl.sets),
+            ["fighter/mario/param/vl.prc", "fighter/luigi/param/vl.prc"]
+        );
+        let mut file = sample();
+        randl.apply_seeded(PRC_PATH, &mut file, 0).unwrap();
+        assert_eq!(float(&file, "walk_speed"), 3.0);
+    }
+
+    #[test]
+    fn entries_roll_independently() {
+        let damage = r#"attack_data.*.damage { return expr="original * rand(0.5, 1.5)" }"#;
+        let walk = r#"walk_speed { return from=0.5 to=3.0 }"#;
+        let run = r#"run_speed { return from=0.5 to=3.0 }"#;
+
+        let alone = apply(&format!("{}\n{}", damage, walk), 99).unwrap();
+        // adding an entry in between doesn't change the others
+        let added = apply(&format!("{}\n{}\n{}", damage, run, walk), 99).unwrap();
+        assert_eq!(hitboxes(&alone, "damage"), hitboxes(&added, "damage"));
+        assert_eq!(float(&alone, "walk_speed"), float(&added, "walk_speed"));
+        assert_ne!(float(&added, "run_speed"), 2.0);
+
+        // neither does loading the files in a different order
+        let (first, second) = (parse(&format!("{}\n{}", damage, run)), parse(walk));
+        let mut forward = sample();
+        first.apply_seeded(PRC_PATH, &mut forward, 99).unwrap();
+        second.apply_seeded(PRC_PATH, &mut forward, 99).unwrap();
+        let mut backward = sample();
+        second.apply_seeded(PRC_PATH, &mut backward, 99).unwrap();
+        first.apply_seeded(PRC_PATH, &mut backward, 99).unwrap();
+        assert_eq!(format!("{:?}", forward), format!("{:?}", added));
+        assert_eq!(format!("{:?}", backward), format!("{:?}", added));
+    }
+
+    #[test]
+    fn failed_apply_rolls_back() {
+        let entries = r#"
+            walk_speed { return 3.0 }
+            attack_data.*.damage { return multiply=2 }
+            attack_data.*.start_frame { return 300 }
+        "#;
+        let mut file = sample();
+        let errors = parse(entries).apply_seeded(PRC_PATH, &mut file, 0).unwrap_err();
+        // every hitbox fails, and the entries that worked are undone
+        assert_eq!(errors.0.len(), 4);
+        assert_eq!(format!("{:?}", file), format!("{:?}", sample()));
+
+        let mut file = sample();
+        let (report, errors) = parse(entries).apply_best_effort(PRC_PATH, &mut file, 0);
+        assert_eq!(errors.len(), 4);
+        assert_eq!(report.changes.len(), 5);
+        assert_eq!(float(&file, "walk_speed"), 3.0);
+        assert_eq!(hitboxes(&file, "damage"), [16.0, 24.0, 9.0, 0.0]);
+        assert_eq!(hitboxes(&file, "start_frame"), [3.0, 10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn lenient_wildcard_counts_skips() {
+        let randl = parse("*?.0.damage { return 1.0 }");
+        let mut file = sample();
+        let report = randl.apply_with_report(PRC_PATH, &mut file, 0).unwrap();
+        // only `attack_data` has a `0.damage`, the three speeds and counts are skipped
+        assert_eq!(hitboxes(&file, "damage"), [1.0, 12.0, 4.5, 0.0]);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].path, "*?.0.damage");
+        assert_eq!(report.skipped[0].count, 3);
+
+        assert!(apply("*.0.damage { return 1.0 }", 0).is_err());
+    }
+
+    fn sorted(mut values: Vec<f32>) -> Vec<f32> {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values
+    }
+
+    #[test]
+    fn unique_draws() {
+        for seed in 0..10 {
+            let file = apply_with_sets(
+                r#"
+                set "frames" {
+                    value 1
+                    value 2
+                    value 3
+                    value 4
+                }
+                "#,
+                r#"attack_data.*.start_frame { return set="frames" unique=true }"#,
+                seed,
+            )
+            .unwrap();
+            assert_eq!(sorted(hitboxes(&file, "start_frame")), [1.0, 2.0, 3.0, 4.0]);
+
+            // each inline set keeps track of its own draws
+            let file = apply(
+                r#"
+                attack_data.*.end_frame {
+                    when original="<20" {
+                        return unique=true {
+                            value 1
+                            value 2
+                        }
+                    }
+                    else {
+                        return unique=true {
+                            value 3
+                            value 4
+                        }
+                    }
+                }
+                "#,
+                seed,
+            )
+            .unwrap();
+            let end_frames = hitboxes(&file, "end_frame");
+            assert_eq!(sorted(end_frames[..2].to_vec()), [1.0, 2.0]);
+            assert_eq!(sorted(end_frames[2..].to_vec()), [3.0, 4.0]);
+
+            let file = apply(
+                r#"
+                attack_data.*.start_frame {
+                    return unique=true refill=true {
+                        value 1
+                        value 2
+                    }
+                }
+                "#,
+                seed,
+            )
+            .unwrap();
+            assert_eq!(sorted(hitboxes(&file, "start_frame")), [1.0, 1.0, 2.0, 2.0]);
+        }
+
+        let exhausted = apply(
+            r#"
+            attack_data.*.start_frame {
+                return unique=true {
+                    value 1
+                    value 2
+                    value 3
+                }
+            }
+            "#,
+            0,
+        );
+        assert!(matches!(exhausted.unwrap_err().0[0].error, EvalError::SetExhausted));
+    }
+
+    #[test]
+    fn weighted_sets() {
+        let mut picked = 0;
+        for seed in 0..200 {
+            let file = apply(
+                r#"
+                attack_data.*.start_frame {
+                    return {
+                        value 1 weight=3
+                        value 2
+                        value 3 weight=0
+                    }
+                }
+                "#,
+                seed,
+            )
+            .unwrap();
+            let frames = hitboxes(&file, "start_frame");
+            assert!(!frames.contains(&3.0));
+            picked += frames.iter().filter(|&&frame| frame == 1.0).count();
+        }
+
+        // 3 in 4 of the 800 picks should be 1
+        assert!(560 < picked && picked < 640, "{}", picked);
+    }
+
+    #[test]
+    fn shuffle_keeps_values() {
+        let mut reordered = false;
+        for seed in 0..10 {
+            let file = apply("attack_data.*.damage { shuffle }", seed).unwrap();
+            let damage = hitboxes(&file, "damage");
+            assert_eq!(sorted(damage.clone()), [0.0, 4.5, 8.0, 12.0]);
+            reordered |= damage != [8.0, 12.0, 4.5, 0.0];
+        }
+        assert!(reordered);
+
+        let structs = apply("attack_data.* { shuffle }", 0).unwrap_err();
+        assert!(matches!(structs.0[0].error, EvalError::CannotShuffle(_)));
+    }
+
+    /// `new / old` for each pair, as f64 so the ratios can be compared
+    fn ratios(new: &[f32], old: &[f32]) -> Vec<f64> {
+        new.iter().zip(old).map(|(&new, &old)| new as f64 / old as f64).collect()
+    }
+
+    #[test]
+    fn groups_share_rolls() {
+        let file = apply(
+            r#"
+            group "speed" {
+                walk_speed { return "scale" from=0.5 to=2.0 }
+                run_speed { return "scale" from=0.5 to=2.0 }
+            }
+            group "frames" {
+                attack_data.*.start_frame { return "scale" from=1.0 to=3.0 }
+                attack_data.*.end_frame { return "scale" from=1.0 to=3.0 }
+            }
+            "#,
+            5,
+        )
+        .unwrap();
+
+        let walk = float(&file, "walk_speed") as f64 / 1.5;
+        let run = float(&file, "run_speed") as f64 / 2.0;
+        assert!((walk - run).abs() < 1e-6, "{} {}", walk, run);
+
+        // each hitbox gets its own roll, shared by its start and end frame. the frames are
+        // rounded to ints, so compare the rolls loosely
+        let start = ratios(&hitboxes(&file, "start_frame"), &[3.0, 10.0, 20.0, 30.0]);
+        let end = ratios(&hitboxes(&file, "end_frame"), &[5.0, 14.0, 25.0, 31.0]);
+        for (start, end) in start[1..].iter().zip(&end[1..]) {
+            assert!((start - end).abs() < 0.1, "{} {}", start, end);
+        }
+        assert!((start[1] - start[3]).abs() > 0.1);
+
+        let file = apply(
+            r#"
+            group "frames" mode="once" {
+                attack_data.*.damage { return "scale" from=1.0 to=3.0 }
+            }
+            "#,
+            5,
+        )
+        .unwrap();
+        let damage = ratios(&hitboxes(&file, "damage")[..3], &[8.0, 12.0, 4.5]);
+        assert!((damage[0] - damage[1]).abs() < 1e-6 && (damage[0] - damage[2]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mode_once() {
+        for seed in 0..10 {
+            let file = apply(
+                r#"
+                attack_data.*.start_frame mode="once" { return from=1 to=100 }
+                attack_data.*.damage mode="once" { return multiply=2 }
+                "#,
+                seed,
+            )
+            .unwrap();
+
+            let frames = hitboxes(&file, "start_frame");
+            assert!(frames.iter().all(|&frame| frame == frames[0]), "{:?}", frames);
+            // worked out from the first match's original
+            assert_eq!(hitboxes(&file, "damage"), [16.0; 4]);
+        }
+    }
+
+    #[test]
+    fn formula_on_non_numeric_param() {
+        for &ret in &[r#"expr="rand(1, 2)""#, r#"dist="exponential" mean=1"#] {
+            let errors = apply(&format!("attack_data.0.kind {{ return {} }}", ret), 0).unwrap_err();
+            assert!(
+                matches!(errors.0[0].error, EvalError::InvalidAssignment("float", "hash40")),
+                "{}",
+                errors
+            );
+        }
+
+        let errors = apply(r#"attack_data.0.kind { return expr="original + 1" }"#, 0).unwrap_err();
+        assert!(matches!(errors.0[0].error, EvalError::NonNumericOriginal("hash40")));
+    }
+
+    #[test]
+    fn range_steps() {
+        let mut seen = Vec::new();
+        for seed in 0..100 {
+            let file = apply(
+                r#"
+                attack_data.*.start_frame { return from=0 to=10 step=5 inclusive=true }
+                attack_data.*.end_frame { return from=0 to=10 step=4 }
+                walk_speed { return from=0.0 to=1.0 step=0.25 inclusive=true }
+                "#,
+                seed,
+            )
+            .unwrap();
+
+            for frame in hitboxes(&file, "start_frame") {
+                assert!(frame == 0.0 || frame == 5.0 || frame == 10.0, "{}", frame);
+                if !seen.contains(&frame) {
+                    seen.push(frame);
+                }
+            }
+            for frame in hitboxes(&file, "end_frame") {
+                assert!(frame == 0.0 || frame == 4.0 || frame == 8.0, "{}", frame);
+            }
+            let speed = float(&file, "walk_speed");
+            assert!([0.0, 0.25, 0.5, 0.75, 1.0].contains(&speed), "{}", speed);
+        }
+
+        // `to` is reachable with `inclusive=true`
+        assert_eq!(sorted(seen), [0.0, 5.0, 10.0]);
+    }
+
+    #[test]
+    fn chance_weights_and_remainder() {
+        let (mut heavy, mut kept) = (0, 0);
+        for seed in 0..200 {
+            let file = apply(
+                r#"
+                attack_data.*.start_frame {
+                    chance weight=3 { return 1 }
+                    chance weight=1 { return 2 }
+                }
+                attack_data.*.end_frame {
+                    chance percent=25 { return 99 }
+                }
+                "#,
+                seed,
+            )
+            .unwrap();
+
+            let frames = hitboxes(&file, "start_frame");
+            assert!(frames.iter().all(|&frame| frame == 1.0 || frame == 2.0));
+            heavy += frames.iter().filter(|&&frame| frame == 1.0).count();
+
+            // the other 75% leaves the original
+            let end_frames = hitboxes(&file, "end_frame");
+            for (&frame, &original) in end_frames.iter().zip(&[5.0, 14.0, 25.0, 31.0]) {
+                assert!(frame == 99.0 || frame == original, "{}", frame);
+                kept += (frame == original) as usize;
+            }
+        }
+
+        assert!(560 < heavy && heavy < 640, "{}", heavy);
+        assert!(560 < kept && kept < 640, "{}", kept);
+
+        let over = RandlFile::from_str(
+            r#"
+            file "x" {
+                walk_speed {
+                    chance percent=60 { return 1.0 }
+                    chance percent=60 { return 2.0 }
+                }
+            }
+            "#,
+        );
+        assert!(matches!(over.unwrap_err().0[0].error, ParseError::ChanceOverflow(_)));
+    }
+
+    #[test]
+    fn when_branches() {
+        let file = apply(
+            r#"
+            attack_data.*.damage {
+                when original=">10" {
+                    return 1.0
+                }
+                when original="<8" {
+                    return 2.0
+                }
+                else {
+                    return 3.0
+                }
+            }
+            attack_data.*.kind {
+                if hash40="collision_attr_fire" {
+                    return hash40="collision_attr_ice"
+                }
+            }
+            attack_data.*.end_frame {
+                when original="==5" {
+                    return 50
+                }
+            }
+            "#,
+            0,
+        )
+        .unwrap();
+
+        // the first branch that matches is taken
+        assert_eq!(hitboxes(&file, "damage"), [3.0, 1.0, 2.0, 2.0]);
+        let kinds: Vec<_> = hitbox_fields(&file, "kind")
+            .into_iter()
+            .map(|kind| match kind {
+                ParamKind::Hash(hash) => *hash,
+                other => panic!("kind is {:?}", other),
+            })
+            .collect();
+        let normal = prc::hash40::hash40("collision_attr_normal");
+        let ice = prc::hash40::hash40("collision_attr_ice");
+        assert_eq!(kinds, [normal, normal, ice, normal]);
+        // without an `else`, anything not matched keeps its original value
+        assert_eq!(hitboxes(&file, "end_frame"), [50.0, 14.0, 25.0, 31.0]);
 
-        Ok(self.0[rand::thread_rng().gen_range(0..len)].clone())
+        let errors = apply(r#"walk_speed { when original="fast" { return 1.0 } }"#, 0);
+        assert!(matches!(errors.unwrap_err().0[0].error, EvalError::InvalidComparison(..)));
     }
 }
@@ -0,0 +1,116 @@
+//! Names for the hashes fields are keyed by.
+//!
+//! prc files only store the `Hash40` of each field's name, so matching fields against a pattern
+//! like `*_speed` needs a list of known names to check. Any hash without a known name can't be
+//! matched by a pattern, but can still be written out in full.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::{fs, io, path::Path};
+
+use prc::hash40::{hash40, Hash40};
+
+lazy_static::lazy_static! {
+    static ref LABELS: RwLock<HashMap<Hash40, String>> = RwLock::new(HashMap::new());
+}
+
+/// Add every label in a `ParamLabels.csv`-style list, where each line is either `hash,label` or
+/// just a label. Returns how many labels were read.
+pub fn add_labels(labels: &str) -> usize {
+    let mut map = LABELS.write().unwrap();
+    let mut count = 0;
+    for line in labels.lines() {
+        // the hash is always the hash of the label, so only the label is needed
+        let label = line.rsplit(',').next().unwrap_or("").trim();
+        if !label.is_empty() {
+            map.insert(hash40(label), label.to_owned());
+            count += 1;
+        }
+    }
+
+    count
+}
+
+/// Read labels from a file, see `add_labels`
+pub fn load_labels<P: AsRef<Path>>(path: P) -> io::Result<usize> {
+    fs::read_to_string(path).map(|labels| add_labels(&labels))
+}
+
+pub(crate) fn label_of(hash: Hash40) -> Option<String> {
+    LABELS.read().unwrap().get(&hash).cloned()
+}
+
+/// Match a name against a glob, where `*` matches any number of characters and `?` exactly one
+pub(crate) fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where to resume from if the characters after the last `*` stop matching
+    let mut retry = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                retry = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match retry {
+                // let the `*` take one more character and try again
+                Some((star, taken)) => {
+                    p = star + 1;
+                    n = taken + 1;
+                    retry = Some((star, taken + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob_matches("walk_speed", "walk_speed"));
+        assert!(!glob_matches("walk_speed", "walk_speed_max"));
+        assert!(!glob_matches("walk_speed", "walk"));
+        assert!(glob_matches("", ""));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_matches("*_speed", "walk_speed"));
+        assert!(glob_matches("*_speed", "_speed"));
+        assert!(!glob_matches("*_speed", "walk_speed_max"));
+        assert!(glob_matches("jump_*", "jump_y"));
+        assert!(glob_matches("*speed*", "air_speed_x_stable"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("**", "anything"));
+        // the star has to backtrack past the first `_s`
+        assert!(glob_matches("*_speed", "dash_start_speed"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_matches("d?mage", "damage"));
+        assert!(!glob_matches("d?mage", "dmage"));
+        assert!(glob_matches("?*", "x"));
+        assert!(!glob_matches("?*", ""));
+    }
+
+    #[test]
+    fn glob_unicode() {
+        assert!(glob_matches("?", "é"));
+        assert!(glob_matches("*é", "café"));
+    }
+}
@@ -1,43 +1,130 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{collections::HashMap, fmt, fs, path::Path};
 
 use prc::hash40::Hash40;
 use kdl::{KdlNode, KdlValue};
 
 pub use prc;
+pub use rand;
 
 mod parser;
 mod error;
 mod eval;
+mod labels;
+mod report;
+mod rng;
+mod seed_code;
+mod span;
 
-pub use error::{EvalError, ParseError};
+pub use error::{
+    Diagnostics, EvalDiagnostic, EvalError, ParseDiagnostic, ParseError, SeedCodeError,
+};
+pub use labels::{add_labels, load_labels};
+pub use report::{Change, DisplayParam, Report, Skipped};
+pub use rng::RandlRng;
+pub use seed_code::{fingerprint, SeedCode};
+pub use span::Span;
 
 #[derive(Debug, Clone)]
 pub struct RandlFile {
     pub entries: Vec<RandlEntry>,
     pub sets: HashMap<String, Set>,
+    /// A hash of the file's contents, ignoring comments and formatting
+    pub fingerprint: u64,
 }
 
 impl RandlFile {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ParseError> {
-        Self::from_str(
-            fs::read_to_string(path)
-                .map_err(|_| ParseError::FileReadFail)?
-                .as_str(),
-        )
+    /// Read and parse a config, returning every error found in it
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Diagnostics<ParseDiagnostic>> {
+        let path = path.as_ref();
+        let file = path.display().to_string();
+        let src = fs::read_to_string(path).map_err(|_| ParseDiagnostic {
+            file: Some(file.clone()),
+            ..ParseError::FileReadFail.into()
+        })?;
+
+        Self::from_str(&src).map_err(|Diagnostics(mut errors)| {
+            for error in errors.iter_mut() {
+                error.file = Some(file.clone());
+            }
+            Diagnostics(errors)
+        })
     }
 
-    pub fn from_str(s: &str) -> Result<Self, ParseError> {
-        kdl::parse_document(s)
-            .map(Self::from_nodes)
-            .map_err(ParseError::ParseFail)?
+    /// Parse a config, returning every error found in it along with where it is
+    pub fn from_str(s: &str) -> Result<Self, Diagnostics<ParseDiagnostic>> {
+        let result = kdl::parse_document(s)
+            .map_err(ParseError::ParseFail)
+            .map_err(Diagnostics::from)
+            .and_then(Self::from_nodes);
+
+        result.map_err(|Diagnostics(mut errors)| {
+            let spans = span::node_spans(s);
+            for error in errors.iter_mut() {
+                error.locate(s, &spans);
+            }
+            Diagnostics(errors)
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 enum PrcPathComponent {
     Field(String),
+    /// Every field whose name matches (`*_speed`, `/^dash_.*$/`)
+    Pattern(FieldPattern),
     Index(usize),
+    /// Any number of indices and ranges of them (`-1`, `2..5`, `0,3,7`)
+    Select(Vec<Selection>),
     Wildcard,
+    /// A wildcard that skips children the rest of the path doesn't exist for (`*?`)
+    LenientWildcard,
+    /// Matches any number of levels, including none (`**`). Places the rest of the path doesn't
+    /// exist for are skipped.
+    RecursiveWildcard,
+    /// A wildcard only matching children where every filter holds (`*(kind==hash40:normal)`)
+    Filter { filters: Vec<Filter>, lenient: bool },
+}
+
+impl PrcPathComponent {
+    fn is_lenient(&self) -> bool {
+        matches!(
+            self,
+            PrcPathComponent::LenientWildcard | PrcPathComponent::Filter { lenient: true, .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+enum FieldPattern {
+    /// `*` matches any number of characters, `?` matches one
+    Glob(String),
+    Regex(regex::Regex),
+}
+
+impl fmt::Display for FieldPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldPattern::Glob(glob) => f.write_str(glob),
+            FieldPattern::Regex(regex) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+/// Part of a `Select`. Negative indices count back from the end of the list.
+#[derive(Debug, Clone)]
+enum Selection {
+    Index(i64),
+    /// Every index from the start up to, but not including, the end. Missing bounds are the
+    /// start or end of the list.
+    Range(Option<i64>, Option<i64>),
+}
+
+/// A test against a field of each child a wildcard matches (`id>=3`)
+#[derive(Debug, Clone)]
+struct Filter {
+    /// The field to test, relative to the child
+    path: PrcPath,
+    condition: Condition,
 }
 
 #[derive(Debug, Clone)]
@@ -49,35 +136,210 @@ impl PrcPath {
     }
 }
 
+impl fmt::Display for PrcPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, component) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            match component {
+                PrcPathComponent::Field(field) => f.write_str(field)?,
+                PrcPathComponent::Pattern(pattern) => write!(f, "{}", pattern)?,
+                PrcPathComponent::Index(index) => write!(f, "{}", index)?,
+                PrcPathComponent::Select(selections) => {
+                    for (i, selection) in selections.iter().enumerate() {
+                        if i != 0 {
+                            f.write_str(",")?;
+                        }
+                        match *selection {
+                            Selection::Index(index) => write!(f, "{}", index)?,
+                            Selection::Range(start, end) => {
+                                if let Some(start) = start {
+                                    write!(f, "{}", start)?;
+                                }
+                                f.write_str("..")?;
+                                if let Some(end) = end {
+                                    write!(f, "{}", end)?;
+                                }
+                            }
+                        }
+                    }
+                }
+                PrcPathComponent::Wildcard => f.write_str("*")?,
+                PrcPathComponent::LenientWildcard => f.write_str("*?")?,
+                PrcPathComponent::RecursiveWildcard => f.write_str("**")?,
+                PrcPathComponent::Filter { filters, lenient } => {
+                    f.write_str(if *lenient { "*?(" } else { "*(" })?;
+                    for (i, filter) in filters.iter().enumerate() {
+                        if i != 0 {
+                            f.write_str(",")?;
+                        }
+                        write!(f, "{}{}", filter.path, filter.condition)?;
+                    }
+                    f.write_str(")")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct PrcPathSlice<'a>(&'a [PrcPathComponent]);
 
 impl<'a> PrcPathSlice<'a> {
-    fn pop_front(&mut self) -> Option<&PrcPathComponent> {
+    fn pop_front(&mut self) -> Option<&'a PrcPathComponent> {
         let ret = self.0.get(0)?;
         self.0 = &self.0[1..];
         Some(ret)
     }
 }
 
+/// A single step from a struct or list to one of its children
+#[derive(Debug, Clone)]
+enum ParamStep {
+    /// The position of the field within the struct, along with its name
+    Field(usize, Hash40),
+    Index(usize),
+}
+
+/// A `PrcPath` resolved against a specific struct, pointing to exactly one param
+#[derive(Debug, Clone, Default)]
+struct ParamPath {
+    steps: Vec<ParamStep>,
+    /// The steps taken by each wildcard in the `PrcPath`, in order
+    wildcards: Vec<ParamStep>,
+}
+
 #[derive(Debug, Clone)]
 pub struct RandlEntry {
     pub prc_name: String,
     prc_fields: Vec<PrcEntry>,
 }
 
+impl RandlEntry {
+    /// Every prc path this entry applies to, with a `{set}` template in the file name expanded
+    /// to each `int` or `string` value in the set. Values repeated in the set only give their
+    /// path once.
+    pub fn prc_paths(&self, sets: &HashMap<String, Set>) -> Vec<String> {
+        let start = match self.prc_name.find('{') {
+            Some(start) => start,
+            None => return vec![self.prc_name.clone()],
+        };
+        let end = match self.prc_name[start..].find('}') {
+            Some(end) => start + end,
+            None => return vec![self.prc_name.clone()],
+        };
+
+        let prefix = &self.prc_name[..start];
+        let suffix = &self.prc_name[end + 1..];
+        sets.get(&self.prc_name[start + 1..end])
+            .map(|set| {
+                let mut paths: Vec<String> = Vec::new();
+                for choice in &set.0 {
+                    let path = match &choice.value {
+                        Value::Int(int) => format!("{}{}{}", prefix, int, suffix),
+                        Value::String(s) => format!("{}{}{}", prefix, s, suffix),
+                        _ => continue,
+                    };
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+
+                paths
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn matches_path(&self, prc_path: &str, sets: &HashMap<String, Set>) -> bool {
+        self.prc_paths(sets).iter().any(|path| path == prc_path)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct PrcEntry {
     path: PrcPath,
     expr: Expr,
+    mode: Mode,
+    /// The group the entry shares its rolls with, if any
+    group: Option<Group>,
+}
+
+/// Entries that share the same random rolls (`group "name" { ... }`)
+#[derive(Debug, Clone)]
+struct Group {
+    name: String,
+    mode: Mode,
+}
+
+/// How the params a path matches are randomized relative to each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Every match gets its own roll (`mode="each"`)
+    Each,
+    /// Every match gets the same roll (`mode="once"`). On an entry, the value picked for the
+    /// first match is written to all of them.
+    Once,
 }
 
 #[derive(Debug, Clone)]
 struct ChanceStmt {
+    /// Always a percent, `weight`s are converted when parsing
     percent: f64,
     expr: Box<Expr>,
 }
 
+/// A branch of a `when` block, taken if every condition holds
+#[derive(Debug, Clone)]
+struct WhenStmt {
+    conditions: Vec<Condition>,
+    expr: Box<Expr>,
+}
+
+/// A test against the param's original value (`original=">10"`)
+#[derive(Debug, Clone)]
+struct Condition {
+    op: CmpOp,
+    /// An int or float for every `op`, or a string, bool, or hash40 for `Eq` and `Ne`
+    value: Value,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.op {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        })?;
+        match &self.value {
+            Value::Int(int) => write!(f, "{}", int),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Hash40(hash) => write!(f, "hash40:{}", hash),
+            Value::Original => f.write_str("original"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// How far off 100% chance percents can add up to while still counting as 100%
+const CHANCE_EPSILON: f64 = 1e-9;
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Int(i64),
@@ -88,26 +350,118 @@ pub enum Value {
     Original,
 }
 
+/// A range of numbers to pick from, every one equally likely
 #[derive(Debug, Clone)]
 enum Range {
-    Int(i64, i64),
+    /// `count` integers `step` apart, starting at `from`
+    Int { from: i64, step: i64, count: i64 },
+    /// Any float from `from` up to `to`
     Float(f64, f64),
+    /// `count` floats `step` apart, starting at `from`
+    FloatSteps { from: f64, step: f64, count: i64 },
+}
+
+/// A random number drawn from a distribution, optionally clamped to `min`/`max`
+/// (`return dist="normal" mean=10 stddev=2`)
+#[derive(Debug, Clone)]
+struct Distribution {
+    kind: DistKind,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+enum DistKind {
+    Normal { mean: f64, stddev: f64 },
+    /// A normal distribution centered on the param's original value, with a standard deviation
+    /// as a percentage of it (`around="original" sd_percent=10`)
+    NormalAround { sd_percent: f64 },
+    Triangular { from: f64, peak: f64, to: f64 },
+    /// Every order of magnitude between `from` and `to` is equally likely
+    LogUniform { from: f64, to: f64 },
+    Exponential { mean: f64 },
+    /// A beta distribution scaled from `[0, 1]` to `[from, to]`
+    Beta { alpha: f64, beta: f64, from: f64, to: f64 },
 }
 
 #[derive(Debug, Clone)]
 enum Return {
     Constant(Value),
     Range(Range),
-    Set(String),
-    AnonymousSet(Set),
+    Multiply(f64),
+    Add(f64),
+    Scale(f64, f64),
+    Formula(Formula),
+    Distribution(Distribution),
+    Set(String, Draw),
+    /// A set written inside the `return`, numbered by its place in the entry so `unique` draws
+    /// from it can be told apart from other inline sets
+    AnonymousSet(Set, Draw, usize),
+}
+
+/// How each param a path matches picks from a set
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Draw {
+    /// Every param picks from the whole set
+    Independent,
+    /// Every param gets a different value, erroring once the set runs out (`unique=true`)
+    Unique,
+    /// Every param gets a different value until the set runs out, then it starts over
+    /// (`unique=true refill=true`)
+    UniqueRefill,
 }
 
 #[derive(Debug, Clone)]
-pub struct Set(pub Vec<Value>);
+pub struct Set(pub Vec<SetValue>);
+
+#[derive(Debug, Clone)]
+pub struct SetValue {
+    pub value: Value,
+    /// How likely the value is to be picked relative to the rest of the set. Defaults to 1.
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Rand,
+    RandInt,
+    Min,
+    Max,
+    Clamp,
+    Round,
+    Floor,
+    Ceil,
+    Abs,
+}
+
+/// An arithmetic expression such as `clamp(original + rand(-3, 3), 1, 60)`
+#[derive(Debug, Clone)]
+enum Formula {
+    Number(f64),
+    Original,
+    /// The value of another param in the same file
+    Ref(PrcPath),
+    Neg(Box<Formula>),
+    BinOp(BinOp, Box<Formula>, Box<Formula>),
+    Call(Function, Vec<Formula>),
+}
 
 #[derive(Debug, Clone)]
 enum Expr {
     Random(Vec<ChanceStmt>),
+    /// The first branch whose conditions hold, otherwise the `else` branch if there is one
+    When(Vec<WhenStmt>, Option<Box<Expr>>),
     Return(Return),
     Original,
+    /// Swap the values of every param a path matches around (`shuffle`)
+    Shuffle,
 }
@@ -1,103 +1,661 @@
 use super::*;
 use ParseError as Error;
 
+type Errors = Diagnostics<ParseDiagnostic>;
+
+use crate::rng::SeedHasher;
 use prc::hash40;
 
+fn hash_nodes(mut hasher: SeedHasher, nodes: &[KdlNode]) -> SeedHasher {
+    hasher = hasher.u64(nodes.len() as u64);
+    for node in nodes {
+        hasher = hasher.str(&node.name).u64(node.values.len() as u64);
+        for value in node.values.iter() {
+            hasher = hash_value(hasher, value);
+        }
+
+        // property order isn't preserved, so hash them sorted
+        let mut properties: Vec<_> = node.properties.iter().collect();
+        properties.sort_by(|a, b| a.0.cmp(b.0));
+        hasher = hasher.u64(properties.len() as u64);
+        for (name, value) in properties {
+            hasher = hash_value(hasher.str(name), value);
+        }
+
+        hasher = hash_nodes(hasher, &node.children);
+    }
+
+    hasher
+}
+
+fn hash_value(hasher: SeedHasher, value: &KdlValue) -> SeedHasher {
+    match value {
+        KdlValue::Int(int) => hasher.u64(0).u64(*int as u64),
+        KdlValue::Float(f) => hasher.u64(1).u64(f.to_bits()),
+        KdlValue::String(s) => hasher.u64(2).str(s),
+        KdlValue::Boolean(b) => hasher.u64(3).u64(*b as u64),
+        KdlValue::Null => hasher.u64(4),
+    }
+}
+
 impl RandlFile {
-    pub fn from_nodes(nodes: Vec<KdlNode>) -> Result<Self, Error> {
+    pub fn from_nodes(nodes: Vec<KdlNode>) -> Result<Self, Errors> {
+        let fingerprint = hash_nodes(SeedHasher::new(0), &nodes).finish();
         let mut sets = HashMap::new();
         let mut entries = Vec::new();
-        for node in nodes.into_iter() {
-            match node.name.as_str() {
-                "set" => {
-                    let (name, set) = Set::from_node(node)?;
+        // the node each entry came from, for errors found once every set is known
+        let mut entry_nodes = Vec::new();
+        let mut errors = Vec::new();
+        for (i, node) in nodes.into_iter().enumerate() {
+            let result = match node.name.as_str() {
+                "set" => Set::from_node(node).map(|(name, set)| {
                     sets.insert(name, set);
-                }
-                "file" => {
-                    entries.push(RandlEntry::from_node(node)?);
-                }
-                _ => {
-                    return Err(Error::InvalidRandlEntry(
-                        "entries must be of type `file` or `set`",
-                    ))
-                }
+                }),
+                "file" => RandlEntry::from_node(node).map(|entry| {
+                    entries.push(entry);
+                    entry_nodes.push(i);
+                }),
+                _ => Err(Errors::from(Error::InvalidRandlEntry(
+                    "entries must be of type `file` or `set`",
+                ))),
+            };
+
+            if let Err(Diagnostics(mut errs)) = in_child(i, result) {
+                errors.append(&mut errs);
             }
         }
 
-        Ok(Self { sets, entries })
+        // sets can come after the files using them, so templates are checked last
+        for (entry, &i) in entries.iter().zip(entry_nodes.iter()) {
+            let result = entry.check_template(&sets).map_err(Errors::from);
+            if let Err(Diagnostics(mut errs)) = in_child(i, result) {
+                errors.append(&mut errs);
+            }
+        }
+        errors.sort_by(|a, b| a.at.cmp(&b.at));
+
+        if errors.is_empty() {
+            Ok(Self {
+                sets,
+                entries,
+                fingerprint,
+            })
+        } else {
+            Err(Diagnostics(errors))
+        }
+    }
+}
+
+/// Combine a node's own error with the errors of its children, so neither hides the other
+fn both<A, B>(a: Result<A, Error>, b: Result<B, Errors>) -> Result<(A, B), Errors> {
+    match (a, b) {
+        (Ok(a), Ok(b)) => Ok((a, b)),
+        (Ok(_), Err(errors)) => Err(errors),
+        (Err(err), Ok(_)) => Err(err.into()),
+        (Err(err), Err(Diagnostics(mut errors))) => {
+            errors.insert(0, err.into());
+            Err(Diagnostics(errors))
+        }
+    }
+}
+
+/// Point every error at the `i`th child of the node being parsed
+fn in_child<T>(i: usize, result: Result<T, Errors>) -> Result<T, Errors> {
+    result.map_err(|Diagnostics(mut errors)| {
+        for error in errors.iter_mut() {
+            error.at.insert(0, i);
+        }
+        Diagnostics(errors)
+    })
+}
+
+/// Parse each child of a node, collecting every error
+fn children<T, F>(nodes: Vec<KdlNode>, mut f: F) -> Result<Vec<T>, Errors>
+where
+    F: FnMut(KdlNode) -> Result<T, Errors>,
+{
+    Diagnostics::collect(
+        nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| in_child(i, f(node))),
+    )
+}
+
+fn number(value: &KdlValue) -> Option<f64> {
+    match *value {
+        KdlValue::Int(int) => Some(int as f64),
+        KdlValue::Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+/// Split `s` on `sep`, ignoring any inside parentheses, brackets, quotes, or `/regexes/`
+fn split_outside(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut regex = false;
+    let mut start = 0;
+    let mut skip = 0;
+    for (i, c) in s.char_indices() {
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+
+        match c {
+            '"' if !regex => quoted = !quoted,
+            // regexes can contain anything, so treat them like quotes
+            '/' if !quoted && (regex || s[start..i].is_empty()) => regex = !regex,
+            _ if regex => {}
+            '(' | '[' if !quoted => depth += 1,
+            ')' | ']' if !quoted => depth -= 1,
+            // `..` is a range rather than two separators, so `a...3` is `a` then `..3`, and
+            // `2...b` is `2..` then `b`
+            '.' if sep == '.' && depth == 0 && !quoted => {
+                let run = s[i..].chars().take_while(|&c| c == '.').count();
+                let current = &s[start..i];
+                let split = match run {
+                    1 => Some(i),
+                    2 => None,
+                    _ if !current.is_empty()
+                        && current.chars().all(|c| c.is_ascii_digit() || c == '-') =>
+                    {
+                        Some(i + run - 1)
+                    }
+                    _ => Some(i),
+                };
+
+                skip = run - 1;
+                if let Some(split) = split {
+                    parts.push(&s[start..split]);
+                    start = split + 1;
+                }
+            }
+            _ if c == sep && depth == 0 && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
     }
+    parts.push(&s[start..]);
+    parts
 }
 
 impl PrcPathComponent {
-    fn from_str(s: &str) -> Result<Self, Error> {
-        if s.chars().all(char::is_numeric) {
-            Ok(PrcPathComponent::Index(s.parse().unwrap()))
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        if s.is_empty() {
+            Err("paths can't have empty components")
+        } else if s.len() >= 2 && s.starts_with('/') && s.ends_with('/') {
+            regex::Regex::new(&s[1..s.len() - 1])
+                .map(|regex| PrcPathComponent::Pattern(FieldPattern::Regex(regex)))
+                .map_err(|_| "invalid regex")
+        } else if s.chars().all(char::is_numeric) {
+            s.parse().map(PrcPathComponent::Index).map_err(|_| "index is too large")
+        } else if s.chars().all(|c| c.is_ascii_digit() || c == '-' || c == '.' || c == ',') {
+            s.split(',')
+                .map(Selection::from_str)
+                .collect::<Result<_, _>>()
+                .map(PrcPathComponent::Select)
+        } else if s == "*" {
+            Ok(PrcPathComponent::Wildcard)
+        } else if s == "*?" {
+            Ok(PrcPathComponent::LenientWildcard)
+        } else if s == "**" {
+            Ok(PrcPathComponent::RecursiveWildcard)
+        } else if s.starts_with('*') && s.ends_with(')') {
+            let start = s.find('(').ok_or("unmatched `)`")?;
+            let lenient = match &s[..start] {
+                "*" => false,
+                "*?" => true,
+                _ => return Err("filters go straight after a `*` or `*?`, like `*(id>=3)`"),
+            };
+            let filters = split_outside(&s[start + 1..s.len() - 1], ',')
+                .into_iter()
+                .map(Filter::from_str)
+                .collect::<Result<_, _>>()?;
+
+            Ok(PrcPathComponent::Filter { filters, lenient })
+        } else if s.contains(&['(', ')', '[', ']'][..]) {
+            Err("filters can only follow a `*` or `*?`, like `attack_data.*(id>=3)`")
+        } else if s.contains(&['*', '?'][..]) {
+            Ok(PrcPathComponent::Pattern(FieldPattern::Glob(s.to_owned())))
         } else {
-            if s == "*" {
-                Ok(PrcPathComponent::Wildcard)
-            } else {
-                Ok(PrcPathComponent::Field(s.to_owned()))
+            Ok(PrcPathComponent::Field(s.to_owned()))
+        }
+    }
+}
+
+impl Selection {
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        let index = |s: &str| {
+            s.parse::<i64>()
+                .map_err(|_| "indices must be integers, like `3`, `-1`, `2..5`, or `0,3,7`")
+        };
+
+        match s.find("..") {
+            Some(split) => {
+                let bound = |s: &str| if s.is_empty() { Ok(None) } else { index(s).map(Some) };
+                let start = bound(&s[..split])?;
+                let end = bound(&s[split + 2..])?;
+                if let (Some(start), Some(end)) = (start, end) {
+                    // ranges mixing negative and positive bounds depend on the list's length
+                    if (start < 0) == (end < 0) && start > end {
+                        return Err("the start of a range can't be after its end");
+                    }
+                }
+
+                Ok(Selection::Range(start, end))
             }
+            None => index(s).map(Selection::Index),
+        }
+    }
+}
+
+impl Filter {
+    /// Parse a filter like `id>=3`, `kind==hash40:normal`, or `name=="mario"`
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        let s = s.trim();
+        let start = s
+            .find(&['=', '!', '<', '>'][..])
+            .ok_or("filters need a comparison, like `id>=3`")?;
+        let path = PrcPath::from_str_inner(s[..start].trim())?;
+        let wildcard = path.0.iter().any(|component| {
+            !matches!(component, PrcPathComponent::Field(_) | PrcPathComponent::Index(_))
+        });
+        if wildcard {
+            return Err("the field a filter checks can't contain wildcards");
+        }
+
+        let (op, rest) = CmpOp::from_prefix(&s[start..]);
+        if rest.len() == s.len() - start {
+            return Err("filters compare with `==`, `!=`, `<`, `<=`, `>`, or `>=`");
         }
+        let rest = rest.trim();
+        let ordered = op != CmpOp::Eq && op != CmpOp::Ne;
+        let condition = if rest.starts_with("hash40:") {
+            if ordered {
+                return Err("hash40s can only be compared with `==` or `!=`");
+            }
+            let name = rest.trim_start_matches("hash40:").trim().trim_matches('"');
+            Condition {
+                op,
+                value: Value::Hash40(hash40::hash40(name)),
+            }
+        } else if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+            if ordered {
+                return Err("`<`, `<=`, `>`, and `>=` can only compare numbers");
+            }
+            Condition {
+                op,
+                value: Value::String(rest[1..rest.len() - 1].to_owned()),
+            }
+        } else {
+            Condition::parse_value(op, rest)?
+        };
+
+        Ok(Filter { path, condition })
     }
 }
 
 impl RandlEntry {
-    fn from_node(mut node: KdlNode) -> Result<Self, Error> {
+    fn from_node(mut node: KdlNode) -> Result<Self, Errors> {
         match node.values.len() {
             0 => Err(Error::InvalidRandlEntry(
                 "entries must contain a filename pattern",
-            )),
+            ).into()),
             1 => match node.values.pop().unwrap() {
-                KdlValue::String(prc_name) => Ok(Self {
-                    prc_name,
-                    prc_fields: node
-                        .children
-                        .into_iter()
-                        .map(PrcEntry::from_node)
-                        .collect::<Result<_, _>>()?,
-                }),
-                _ => Err(Error::InvalidRandlEntry("filename must be string")),
+                KdlValue::String(prc_name) => {
+                    let template = match (prc_name.matches('{').count(), prc_name.matches('}').count()) {
+                        (0, 0) | (1, 1) => Ok(()),
+                        (open, close) if open == close => Err(Error::InvalidRandlEntry(
+                            "only one `{set}` template per file name",
+                        )),
+                        _ => Err(Error::InvalidRandlEntry("unmatched `{` or `}` in file name")),
+                    };
+                    let fields = children(node.children, |node| {
+                        if node.name == "group" {
+                            Group::from_node(node)
+                        } else {
+                            PrcEntry::from_node(node).map(|field| vec![field])
+                        }
+                    });
+                    let (_, fields) = both(template, fields)?;
+
+                    Ok(Self {
+                        prc_name,
+                        prc_fields: fields.into_iter().flatten().collect(),
+                    })
+                }
+                _ => Err(Error::InvalidRandlEntry("filename must be string").into()),
             },
-            _ => Err(Error::InvalidRandlEntry("only one file name per entry")),
+            _ => Err(Error::InvalidRandlEntry("only one file name per entry").into()),
+        }
+    }
+
+    /// Make sure the set in a `{set}` template exists and has values to fill it in with
+    fn check_template(&self, sets: &HashMap<String, Set>) -> Result<(), Error> {
+        let start = match self.prc_name.find('{') {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let end = match self.prc_name.find('}') {
+            Some(end) if end > start => end,
+            _ => return Err(Error::InvalidRandlEntry("unmatched `{` or `}` in file name")),
+        };
+
+        let name = &self.prc_name[start + 1..end];
+        match sets.get(name) {
+            Some(set) => {
+                let usable = set
+                    .0
+                    .iter()
+                    .any(|choice| matches!(choice.value, Value::Int(_) | Value::String(_)));
+                if usable {
+                    Ok(())
+                } else {
+                    Err(Error::EmptyTemplateSet(name.to_owned()))
+                }
+            }
+            None => Err(Error::UnknownTemplateSet(name.to_owned())),
         }
     }
 }
 
+impl Mode {
+    fn from_properties(properties: &HashMap<String, KdlValue>) -> Result<Self, Error> {
+        match properties.get("mode") {
+            Some(KdlValue::String(mode)) if mode == "each" => Ok(Mode::Each),
+            Some(KdlValue::String(mode)) if mode == "once" => Ok(Mode::Once),
+            Some(_) => Err(Error::InvalidMode),
+            None => Ok(Mode::Each),
+        }
+    }
+}
+
+impl Group {
+    /// Parse a group and every entry in it
+    fn from_node(mut node: KdlNode) -> Result<Vec<PrcEntry>, Errors> {
+        let name = match node.values.len() {
+            0 => Err(Error::InvalidGroup("groups must have a name")),
+            1 => match node.values.pop().unwrap() {
+                KdlValue::String(name) if !name.is_empty() => Ok(name),
+                _ => Err(Error::InvalidGroup("group name must be a non-empty string")),
+            },
+            _ => Err(Error::InvalidGroup("only one name per group")),
+        };
+        let group = name.and_then(|name| {
+            let mode = Mode::from_properties(&node.properties)?;
+            Ok(Group { name, mode })
+        });
+
+        let fields = children(node.children, |node| {
+            if node.name == "group" {
+                Err(Error::InvalidGroup("groups can't be nested").into())
+            } else {
+                PrcEntry::from_node(node)
+            }
+        });
+
+        both(group, fields).map(|(group, mut fields)| {
+            for field in fields.iter_mut() {
+                field.group = Some(group.clone());
+            }
+            fields
+        })
+    }
+}
+
 impl ChanceStmt {
-    fn from_nodes(nodes: Vec<KdlNode>) -> Result<Vec<Self>, Error> {
-        if nodes.iter().all(|node| node.name == "chance") {
-            let nodes: Vec<ChanceStmt> = nodes
-                .into_iter()
-                .map(|node| {
-                    Ok(ChanceStmt {
-                        percent: match node.properties.get("percent").ok_or(Error::NoPercent)? {
-                            &KdlValue::Int(int) => int as f64,
-                            &KdlValue::Float(f) => f,
-                            _ => {
-                                return Err(Error::InvalidChance(
-                                    "percent must be an integer or a float",
-                                ))
-                            }
-                        },
-                        expr: Box::new(Expr::from_nodes(node.children)?),
-                    })
-                })
-                .collect::<Result<_, _>>()?;
+    fn from_nodes(nodes: Vec<KdlNode>) -> Result<Vec<Self>, Errors> {
+        if let Some(i) = nodes.iter().position(|node| node.name != "chance") {
+            in_child(i, Err(Error::MixedExprs.into()))
+        } else {
+            let mut chances = Vec::new();
+            // the index, kind, and value of every chance with valid odds, to check the block as a
+            // whole even when some of them have errors
+            let mut odds = Vec::new();
+            let mut errors = Vec::new();
+            for (i, node) in nodes.into_iter().enumerate() {
+                let number = |key| match node.properties.get(key) {
+                    Some(value) => match number(value) {
+                        Some(x) if x >= 0.0 && x.is_finite() => Ok(Some(x)),
+                        _ => Err(Error::InvalidChance(
+                            "`percent` and `weight` must be non-negative integers or floats",
+                        )),
+                    },
+                    None => Ok(None),
+                };
+                let chance_odds = match (number("percent"), number("weight")) {
+                    (Ok(Some(_)), Ok(Some(_))) => Err(Error::InvalidChance(
+                        "chances take either a `percent` or a `weight`, not both",
+                    )),
+                    (Ok(Some(percent)), Ok(None)) => Ok((false, percent)),
+                    (Ok(None), Ok(Some(weight))) => Ok((true, weight)),
+                    (Ok(None), Ok(None)) => Err(Error::NoPercent),
+                    (Err(err), _) | (_, Err(err)) => Err(err),
+                };
+                if let Ok((weighted, percent)) = chance_odds {
+                    odds.push((i, weighted, percent));
+                }
 
-            let total: f64 = nodes.iter().map(|chance| chance.percent).sum();
+                // check the chance's children even if its percent is invalid
+                match in_child(i, both(chance_odds, Expr::from_nodes(node.children))) {
+                    Ok(((_, percent), expr)) => chances.push(ChanceStmt {
+                        percent,
+                        expr: Box::new(expr),
+                    }),
+                    Err(Diagnostics(mut errs)) => errors.append(&mut errs),
+                }
+            }
 
-            if 100.0 - total <= 0.1 {
-                Ok(nodes)
+            let weighted = odds.first().map(|&(_, weighted, _)| weighted).unwrap_or(false);
+            let total: f64 = odds.iter().map(|&(_, _, percent)| percent).sum();
+            let block_error = if let Some(&(i, ..)) = odds.iter().find(|&&(_, other, _)| other != weighted) {
+                Some(in_child(i, Err::<(), _>(Error::InvalidChance(
+                    "`percent` and `weight` can't be mixed in the same block",
+                ).into())))
+            } else if weighted && total == 0.0 {
+                Some(Err(Error::InvalidChance("chance weights can't all be 0").into()))
+            } else if !weighted && total > 100.0 + CHANCE_EPSILON {
+                Some(Err(Error::ChanceOverflow(total).into()))
             } else {
-                Err(Error::InvalidChance(
-                    "Chance statements must add up to >99.9%",
-                ))
+                None
+            };
+            if let Some(Err(Diagnostics(mut errs))) = block_error {
+                errs.append(&mut errors);
+                errors = errs;
+            }
+
+            if !errors.is_empty() {
+                return Err(Diagnostics(errors));
+            }
+
+            if weighted {
+                for chance in chances.iter_mut() {
+                    chance.percent = chance.percent / total * 100.0;
+                }
+            }
+
+            Ok(chances)
+        }
+    }
+}
+
+impl Expr {
+    /// Number every set written inside a `return`, in the order they're written
+    fn number_inline_sets(&mut self, next: &mut usize) {
+        match self {
+            Expr::Random(chances) => {
+                for chance in chances {
+                    chance.expr.number_inline_sets(next);
+                }
+            }
+            Expr::When(whens, otherwise) => {
+                for when in whens {
+                    when.expr.number_inline_sets(next);
+                }
+                if let Some(otherwise) = otherwise {
+                    otherwise.number_inline_sets(next);
+                }
             }
+            Expr::Return(Return::AnonymousSet(_, _, id)) => {
+                *id = *next;
+                *next += 1;
+            }
+            Expr::Return(_) | Expr::Original | Expr::Shuffle => {}
+        }
+    }
+}
+
+impl WhenStmt {
+    /// Parse a `when` followed by any number of other `when`s and an optional `else`
+    fn from_nodes(nodes: Vec<KdlNode>) -> Result<Expr, Errors> {
+        let count = nodes.len();
+        let mut i = 0;
+        let branches = children(nodes, |node| {
+            i += 1;
+            let conditions = match &*node.name {
+                "when" | "if" => Condition::from_node(&node).map(Some),
+                "else" if i != count => Err(Error::InvalidWhen("`else` has to come last")),
+                "else" if !node.values.is_empty() || !node.properties.is_empty() => {
+                    Err(Error::InvalidWhen("`else` doesn't take any conditions"))
+                }
+                "else" => Ok(None),
+                _ => Err(Error::InvalidWhen(
+                    "only `when`, `if`, and `else` can follow a `when`",
+                )),
+            };
+
+            // check the branch's children even if its conditions are invalid
+            both(conditions, Expr::from_nodes(node.children))
+        })?;
+
+        let mut whens = Vec::new();
+        let mut otherwise = None;
+        for (conditions, expr) in branches {
+            match conditions {
+                Some(conditions) => whens.push(WhenStmt {
+                    conditions,
+                    expr: Box::new(expr),
+                }),
+                None => otherwise = Some(Box::new(expr)),
+            }
+        }
+
+        Ok(Expr::When(whens, otherwise))
+    }
+}
+
+impl Condition {
+    /// Every condition on a `when` node. All of them have to hold for its branch to be taken.
+    fn from_node(node: &KdlNode) -> Result<Vec<Self>, Error> {
+        if !node.values.is_empty() {
+            return Err(Error::InvalidWhen(
+                "conditions are written as properties, like `when original=\">10\"`",
+            ));
+        }
+
+        let mut conditions = Vec::new();
+        // sorted so conditions are always checked in the same order
+        let mut properties: Vec<_> = node.properties.iter().collect();
+        properties.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, value) in properties {
+            conditions.push(match (key.as_str(), value) {
+                ("original", KdlValue::String(s)) => {
+                    let (op, rest) = CmpOp::from_prefix(s);
+                    // only trim after an operator, so strings can still be matched exactly
+                    let rest = if rest.len() == s.len() { rest } else { rest.trim() };
+                    Condition::parse_value(op, rest).map_err(Error::InvalidWhen)?
+                }
+                ("original", KdlValue::Int(int)) => Condition {
+                    op: CmpOp::Eq,
+                    value: Value::Int(*int),
+                },
+                ("original", KdlValue::Float(f)) => Condition {
+                    op: CmpOp::Eq,
+                    value: Value::Float(*f),
+                },
+                ("original", KdlValue::Boolean(b)) => Condition {
+                    op: CmpOp::Eq,
+                    value: Value::Bool(*b),
+                },
+                ("original", KdlValue::Null) => return Err(Error::InvalidType),
+                ("hash40", KdlValue::String(s)) => {
+                    let (op, name) = CmpOp::from_prefix(s);
+                    if op != CmpOp::Eq && op != CmpOp::Ne {
+                        return Err(Error::InvalidWhen(
+                            "hash40s can only be compared with `==` or `!=`",
+                        ));
+                    }
+
+                    Condition {
+                        op,
+                        value: Value::Hash40(hash40::hash40(name.trim())),
+                    }
+                }
+                ("hash40", &KdlValue::Int(hash)) => Condition {
+                    op: CmpOp::Eq,
+                    value: Value::Hash40(Hash40(hash as u64)),
+                },
+                ("hash40", _) => {
+                    return Err(Error::InvalidWhen("`hash40` must be a string or an integer"))
+                }
+                _ => {
+                    return Err(Error::InvalidWhen(
+                        "only `original` and `hash40` conditions are supported",
+                    ))
+                }
+            });
+        }
+
+        if conditions.is_empty() {
+            Err(Error::InvalidWhen(
+                "`when` needs a condition, like `when original=\">10\"`",
+            ))
         } else {
-            Err(Error::MixedExprs)
+            Ok(conditions)
         }
     }
+
+    /// Parse the value being compared against, as a number if it is one
+    fn parse_value(op: CmpOp, rest: &str) -> Result<Self, &'static str> {
+        let value = if let Ok(int) = rest.parse() {
+            Value::Int(int)
+        } else if let Some(f) = rest.parse::<f64>().ok().filter(|f| f.is_finite()) {
+            Value::Float(f)
+        } else if op == CmpOp::Eq || op == CmpOp::Ne {
+            match rest {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::String(rest.to_owned()),
+            }
+        } else {
+            return Err("`<`, `<=`, `>`, and `>=` can only compare numbers");
+        };
+
+        Ok(Condition { op, value })
+    }
+}
+
+impl CmpOp {
+    /// Split the comparison off the front of a condition, defaulting to `Eq` if there isn't one
+    fn from_prefix(s: &str) -> (Self, &str) {
+        let ops = [
+            (">=", CmpOp::Ge),
+            ("<=", CmpOp::Le),
+            ("==", CmpOp::Eq),
+            ("!=", CmpOp::Ne),
+            (">", CmpOp::Gt),
+            ("<", CmpOp::Lt),
+        ];
+        ops.iter()
+            .find(|(prefix, _)| s.starts_with(prefix))
+            .map(|&(prefix, op)| (op, &s[prefix.len()..]))
+            .unwrap_or((CmpOp::Eq, s))
+    }
 }
 
 impl Value {
@@ -168,8 +726,37 @@ impl Value {
     }
 }
 
+impl SetValue {
+    fn from_node(node: KdlNode) -> Result<Self, Error> {
+        let weight = match node.properties.get("weight") {
+            Some(weight) => match number(weight) {
+                Some(weight) if weight >= 0.0 && weight.is_finite() => weight,
+                _ => {
+                    return Err(Error::InvalidValueStmt(
+                        "`weight` must be a non-negative integer or float",
+                    ))
+                }
+            },
+            None => 1.0,
+        };
+
+        Value::from_node(node).map(|value| SetValue { value, weight })
+    }
+}
+
 impl Set {
-    fn from_node(mut node: KdlNode) -> Result<(String, Self), Error> {
+    fn from_children(nodes: Vec<KdlNode>) -> Result<Self, Errors> {
+        let values = children(nodes, |node| Ok(SetValue::from_node(node)?))?;
+        if values.is_empty() {
+            Err(Error::InvalidSet("sets must contain at least one value").into())
+        } else if values.iter().all(|choice| choice.weight == 0.0) {
+            Err(Error::InvalidSet("at least one value must have a non-zero weight").into())
+        } else {
+            Ok(Set(values))
+        }
+    }
+
+    fn from_node(mut node: KdlNode) -> Result<(String, Self), Errors> {
         let set_name = match node.values.len() {
             0 => Err(Error::InvalidRandlEntry("sets must contain a name")),
             1 => match node.values.pop().unwrap() {
@@ -177,155 +764,949 @@ impl Set {
                 _ => Err(Error::InvalidRandlEntry("set name must be string")),
             },
             _ => Err(Error::InvalidRandlEntry("only one name per set")),
-        }?;
+        };
 
-        node.children
-            .into_iter()
-            .map(Value::from_node)
-            .collect::<Result<_, _>>()
-            .map(Set)
-            .map(move |set| (set_name, set))
+        both(set_name, Set::from_children(node.children))
     }
 }
 
+impl Draw {
+    fn from_properties(properties: &HashMap<String, KdlValue>) -> Result<Self, Error> {
+        let flag = |name, msg| match properties.get(name) {
+            Some(&KdlValue::Boolean(b)) => Ok(b),
+            Some(_) => Err(Error::InvalidReturn(msg)),
+            None => Ok(false),
+        };
+
+        let unique = flag("unique", "`unique` must be a bool")?;
+        let refill = flag("refill", "`refill` must be a bool")?;
+        match (unique, refill) {
+            (false, false) => Ok(Draw::Independent),
+            (true, false) => Ok(Draw::Unique),
+            (true, true) => Ok(Draw::UniqueRefill),
+            (false, true) => Err(Error::InvalidReturn(
+                "`refill` can only be used with `unique=true`",
+            )),
+        }
+    }
+}
+
+/// The error for a return property that isn't part of any return form
+const INVALID_PROPERTY: &str = "Invalid property in return. Only `set`, `to`, `from`, `step`, `inclusive`, `hash40`, `expr`, `multiply`, `add`, `dist`, `unique`, and `refill` are supported";
+
 impl Return {
-    fn from_node(mut node: KdlNode) -> Result<Self, Error> {
+    fn from_node(mut node: KdlNode) -> Result<Self, Errors> {
         assert_eq!(node.name, "return");
 
+        let draw = Draw::from_properties(&node.properties)?;
+
         if node.children.is_empty() {
             if node.properties.is_empty() {
                 match node.values.len() {
                     0 => {
                         return Err(Error::InvalidReturn(
                             "Returns must have either children, properties, or values",
-                        ))
+                        ).into())
+                    }
+                    1 if matches!(&node.values[0], KdlValue::String(mode) if mode == "scale") => {
+                        Err(Error::InvalidReturn(
+                            "Scale returns require both a `from` and `to` property",
+                        ).into())
                     }
                     1 => Ok(Return::Constant(match node.values.pop().unwrap() {
                         KdlValue::Int(int) => Value::Int(int),
                         KdlValue::Float(f) => Value::Float(f),
                         KdlValue::String(s) => Value::String(s),
                         KdlValue::Boolean(b) => Value::Bool(b),
-                        KdlValue::Null => return Err(Error::InvalidType),
+                        KdlValue::Null => return Err(Error::InvalidType.into()),
                     })),
                     _ => {
                         return Err(Error::InvalidReturn(
                             "Returns may not have more than one return value",
-                        ))
+                        ).into())
                     }
                 }
             } else {
-                if !node.values.is_empty() {
+                // `return "scale" from=.. to=..` is the only form allowing both
+                let scale = match node.values.as_slice() {
+                    [] => false,
+                    [KdlValue::String(mode)] if mode == "scale" => true,
+                    _ => {
+                        return Err(Error::InvalidReturn(
+                            "Returns may not have both properties and values",
+                        ).into())
+                    }
+                };
+
+                if draw != Draw::Independent && !node.properties.contains_key("set") {
                     return Err(Error::InvalidReturn(
-                        "Returns may not have both properties and values",
-                    ));
+                        "`unique` can only be used when returning a set",
+                    ).into());
+                }
+
+                if node.properties.contains_key("dist") {
+                    if scale {
+                        return Err(Error::InvalidReturn(
+                            "Distributions can't be used with `scale`",
+                        ).into());
+                    }
+
+                    return Distribution::from_properties(&node.properties)
+                        .map(Return::Distribution)
+                        .map_err(Errors::from);
                 }
 
+                // each form only takes its own properties, anything else is a typo or a second form
+                // that would otherwise be silently ignored
+                let only = |allowed: &[&str]| -> Result<(), Errors> {
+                    const FORMS: &[&str] =
+                        &["set", "to", "from", "hash40", "expr", "multiply", "add", "dist"];
+                    match node.properties.keys().find(|key| !allowed.contains(&key.as_str())) {
+                        Some(key) if FORMS.contains(&key.as_str()) => Err(Error::InvalidReturn(
+                            "Returns can only use one of `set`, `from`/`to`, `hash40`, `expr`, `multiply`, `add`, or `dist`",
+                        ).into()),
+                        Some(_) => Err(Error::InvalidReturn(INVALID_PROPERTY).into()),
+                        None => Ok(()),
+                    }
+                };
+
                 // search properties
                 let from = node.properties.get("from");
                 let to = node.properties.get("to");
                 match (from, to) {
                     (Some(_), None) => {
-                        Err(Error::InvalidReturn("Return range missing `to` property"))
+                        Err(Error::InvalidReturn("Return range missing `to` property").into())
                     }
                     (None, Some(_)) => {
-                        Err(Error::InvalidReturn("Return range missing `from` property"))
+                        Err(Error::InvalidReturn("Return range missing `from` property").into())
                     }
-                    (Some(from), Some(to)) => Ok(Return::Range(match (from, to) {
-                        (&KdlValue::Int(from), &KdlValue::Int(to)) => Range::Int(from, to),
-                        (&KdlValue::Int(from), &KdlValue::Float(to)) => {
-                            Range::Float(from as f64, to)
+                    (Some(from), Some(to)) if scale => {
+                        only(&["from", "to"])?;
+                        match (number(from), number(to)) {
+                            (Some(from), Some(to)) if !from.is_finite() || !to.is_finite() => Err(
+                                Error::InvalidReturn("Scale range must be finite").into(),
+                            ),
+                            (Some(from), Some(to)) if from >= to => Err(Error::InvalidReturn(
+                                "Scale `from` must be less than `to`",
+                            ).into()),
+                            (Some(from), Some(to)) => Ok(Return::Scale(from, to)),
+                            _ => Err(Error::InvalidReturn(
+                                "Scale range must be integers or floats",
+                            ).into()),
                         }
-                        (&KdlValue::Float(from), &KdlValue::Int(to)) => {
-                            Range::Float(from, to as f64)
-                        }
-                        (&KdlValue::Float(from), &KdlValue::Float(to)) => Range::Float(from, to),
-                        _ => {
-                            return Err(Error::InvalidReturn(
-                                "Ranges must be for only integers or floats",
-                            ))
-                        }
-                    })),
+                    }
+                    _ if scale => Err(Error::InvalidReturn(
+                        "Scale returns require both a `from` and `to` property",
+                    ).into()),
+                    (Some(from), Some(to)) => {
+                        only(&["from", "to", "step", "inclusive"])?;
+                        Range::from_properties(from, to, &node.properties)
+                            .map(Return::Range)
+                            .map_err(Errors::from)
+                    }
                     _ => {
                         if let Some(hash40) = node.properties.get("hash40") {
+                            only(&["hash40"])?;
                             let hash40 = match hash40 {
                                 &KdlValue::Int(hash) => Hash40(hash as u64),
                                 KdlValue::String(s) => hash40::hash40(&s),
                                 _ => {
                                     return Err(Error::InvalidReturn(
                                         "`hash40` property must be a string or an integer",
-                                    ))
+                                    ).into())
                                 }
                             };
 
                             Ok(Return::Constant(Value::Hash40(hash40)))
                         } else if let Some(set_name) = node.properties.get("set") {
+                            only(&["set", "unique", "refill"])?;
                             if let KdlValue::String(set_name) = set_name {
-                                Ok(Return::Set(set_name.clone()))
+                                Ok(Return::Set(set_name.clone(), draw))
+                            } else {
+                                Err(Error::InvalidReturn("Return set must be a string").into())
+                            }
+                        } else if let Some(formula) = node.properties.get("expr") {
+                            only(&["expr"])?;
+                            if let KdlValue::String(formula) = formula {
+                                Formula::from_str(formula).map(Return::Formula).map_err(Errors::from)
                             } else {
-                                Err(Error::InvalidReturn("Return set must be a string"))
+                                Err(Error::InvalidReturn("Return expr must be a string").into())
                             }
+                        } else if let Some(factor) = node.properties.get("multiply") {
+                            only(&["multiply"])?;
+                            number(factor).map(Return::Multiply).ok_or_else(|| {
+                                Error::InvalidReturn(
+                                    "`multiply` property must be an integer or a float",
+                                )
+                                .into()
+                            })
+                        } else if let Some(offset) = node.properties.get("add") {
+                            only(&["add"])?;
+                            number(offset).map(Return::Add).ok_or_else(|| {
+                                Error::InvalidReturn("`add` property must be an integer or a float")
+                                    .into()
+                            })
                         } else {
-                            Err(Error::InvalidReturn(
-                                "Invalid property in return. Only `set`, `to`, `from`, and `hash40` are supported"
-                            ))
+                            Err(Error::InvalidReturn(INVALID_PROPERTY).into())
                         }
                     }
                 }
             }
         } else {
-            if !node.properties.is_empty() {
+            if node.properties.keys().any(|key| key != "unique" && key != "refill") {
                 return Err(Error::InvalidReturn(
-                    "Return nodes with children cannot have properties",
-                ));
+                    "Return nodes with children can only have `unique` and `refill` properties",
+                ).into());
             }
             if node.values.is_empty() {
                 // parse children
-                node.children
-                    .into_iter()
-                    .map(Value::from_node)
-                    .collect::<Result<_, _>>()
-                    .map(Set)
-                    .map(Return::AnonymousSet)
+                Set::from_children(node.children).map(|set| Return::AnonymousSet(set, draw, 0))
             } else {
                 return Err(Error::InvalidReturn(
                     "Return nodes with children cannot have values",
-                ));
+                ).into());
+            }
+        }
+    }
+}
+
+impl Range {
+    /// Parse `from=.. to=..` along with the optional `step` and `inclusive` properties
+    fn from_properties(
+        from: &KdlValue,
+        to: &KdlValue,
+        properties: &HashMap<String, KdlValue>,
+    ) -> Result<Self, Error> {
+        let inclusive = match properties.get("inclusive") {
+            Some(&KdlValue::Boolean(inclusive)) => inclusive,
+            Some(_) => return Err(Error::InvalidReturn("`inclusive` must be a bool")),
+            None => false,
+        };
+        let step = properties.get("step");
+
+        if let (&KdlValue::Int(from), &KdlValue::Int(to)) = (from, to) {
+            let step = match step {
+                Some(&KdlValue::Int(step)) => Some(step),
+                Some(&KdlValue::Float(_)) => None,
+                Some(_) => return Err(Error::InvalidReturn("`step` must be an integer or a float")),
+                None => Some(1),
+            };
+
+            if let Some(step) = step {
+                if step <= 0 {
+                    return Err(Error::InvalidReturn("`step` must be greater than 0"));
+                }
+
+                // i128 so huge ranges can't overflow
+                let span = to as i128 - from as i128;
+                let count = if inclusive {
+                    if span < 0 {
+                        return Err(Error::InvalidReturn(
+                            "Range `from` must not be greater than `to`",
+                        ));
+                    }
+                    span / step as i128 + 1
+                } else {
+                    if span <= 0 {
+                        return Err(Error::InvalidReturn(
+                            "Range `from` must be less than `to`, or use `inclusive=true`",
+                        ));
+                    }
+                    (span + step as i128 - 1) / step as i128
+                };
+                if count > i64::MAX as i128 {
+                    return Err(Error::InvalidReturn("Range is too large"));
+                }
+
+                return Ok(Range::Int { from, step, count: count as i64 });
+            }
+        }
+
+        let (from, to) = match (number(from), number(to)) {
+            (Some(from), Some(to)) if from.is_finite() && to.is_finite() => (from, to),
+            _ => return Err(Error::InvalidReturn("Ranges must be for only integers or floats")),
+        };
+        if from > to || (from == to && !inclusive) {
+            return Err(Error::InvalidReturn(if inclusive {
+                "Range `from` must not be greater than `to`"
+            } else {
+                "Range `from` must be less than `to`, or use `inclusive=true`"
+            }));
+        }
+
+        let step = match step {
+            Some(step) => match number(step) {
+                Some(step) if step > 0.0 && step.is_finite() => step,
+                Some(_) => return Err(Error::InvalidReturn("`step` must be greater than 0")),
+                None => return Err(Error::InvalidReturn("`step` must be an integer or a float")),
+            },
+            None => return Ok(Range::Float(from, to)),
+        };
+
+        // allow a little float error, so 0 to 1 in steps of 0.1 can land on 1
+        const EPSILON: f64 = 1e-9;
+        let steps = (to - from) / step;
+        let count = if inclusive {
+            (steps + EPSILON).floor() + 1.0
+        } else {
+            (steps - EPSILON).ceil().max(1.0)
+        };
+        if count > i64::MAX as f64 {
+            return Err(Error::InvalidReturn("Range is too large"));
+        }
+
+        Ok(Range::FloatSteps { from, step, count: count as i64 })
+    }
+}
+
+impl Distribution {
+    fn from_properties(properties: &HashMap<String, KdlValue>) -> Result<Self, Error> {
+        let number = |key| match properties.get(key) {
+            Some(value) => match number(value) {
+                Some(x) if x.is_finite() => Ok(Some(x)),
+                _ => Err(Error::InvalidDistribution(
+                    "distribution properties must be finite integers or floats",
+                )),
+            },
+            None => Ok(None),
+        };
+        let required = |key, missing| number(key)?.ok_or(Error::InvalidDistribution(missing));
+
+        let dist = match properties.get("dist") {
+            Some(KdlValue::String(dist)) => dist.as_str(),
+            _ => return Err(Error::InvalidDistribution("`dist` must be a string")),
+        };
+        let (kind, allowed): (_, &[&str]) = match dist {
+            "normal" if properties.contains_key("around") => {
+                match properties.get("around") {
+                    Some(KdlValue::String(around)) if around == "original" => {}
+                    _ => {
+                        return Err(Error::InvalidDistribution(
+                            "`around` only supports \"original\"",
+                        ))
+                    }
+                }
+                let sd_percent = required("sd_percent", "`around` requires `sd_percent`")?;
+                if sd_percent < 0.0 {
+                    return Err(Error::InvalidDistribution("`sd_percent` can't be negative"));
+                }
+
+                (DistKind::NormalAround { sd_percent }, &["around", "sd_percent"])
+            }
+            "normal" => {
+                let mean = required("mean", "normal distributions require `mean` and `stddev`")?;
+                let stddev = required("stddev", "normal distributions require `mean` and `stddev`")?;
+                if stddev < 0.0 {
+                    return Err(Error::InvalidDistribution("`stddev` can't be negative"));
+                }
+
+                (DistKind::Normal { mean, stddev }, &["mean", "stddev"])
+            }
+            "triangular" => {
+                let missing = "triangular distributions require `from`, `peak`, and `to`";
+                let from = required("from", missing)?;
+                let peak = required("peak", missing)?;
+                let to = required("to", missing)?;
+                if !(from <= peak && peak <= to && from < to) {
+                    return Err(Error::InvalidDistribution(
+                        "triangular distributions need `from` <= `peak` <= `to`, with `from` < `to`",
+                    ));
+                }
+
+                (DistKind::Triangular { from, peak, to }, &["from", "peak", "to"])
+            }
+            "log-uniform" => {
+                let missing = "log-uniform distributions require `from` and `to`";
+                let from = required("from", missing)?;
+                let to = required("to", missing)?;
+                if !(0.0 < from && from < to) {
+                    return Err(Error::InvalidDistribution(
+                        "log-uniform distributions need 0 < `from` < `to`",
+                    ));
+                }
+
+                (DistKind::LogUniform { from, to }, &["from", "to"])
+            }
+            "exponential" => {
+                let mean = required("mean", "exponential distributions require `mean`")?;
+                if mean <= 0.0 {
+                    return Err(Error::InvalidDistribution("`mean` must be positive"));
+                }
+
+                (DistKind::Exponential { mean }, &["mean"])
+            }
+            "beta" => {
+                let missing = "beta distributions require `alpha` and `beta`";
+                let alpha = required("alpha", missing)?;
+                let beta = required("beta", missing)?;
+                let from = number("from")?.unwrap_or(0.0);
+                let to = number("to")?.unwrap_or(1.0);
+                if alpha <= 0.0 || beta <= 0.0 {
+                    return Err(Error::InvalidDistribution("`alpha` and `beta` must be positive"));
+                }
+                if from >= to {
+                    return Err(Error::InvalidDistribution("`from` must be less than `to`"));
+                }
+
+                (DistKind::Beta { alpha, beta, from, to }, &["alpha", "beta", "from", "to"])
+            }
+            _ => {
+                return Err(Error::InvalidDistribution(
+                    "`dist` must be one of \"normal\", \"triangular\", \"log-uniform\", \"exponential\", or \"beta\"",
+                ))
+            }
+        };
+
+        let unknown = properties.keys().any(|key| {
+            let key = key.as_str();
+            !["dist", "min", "max"].contains(&key) && !allowed.contains(&key)
+        });
+        if unknown {
+            return Err(Error::InvalidDistribution(
+                "unsupported property for this distribution, see the README for what each one takes",
+            ));
+        }
+
+        let min = number("min")?;
+        let max = number("max")?;
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(Error::InvalidDistribution("`min` can't be greater than `max`"));
+            }
+        }
+
+        Ok(Distribution { kind, min, max })
+    }
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "rand" => Function::Rand,
+            "randint" => Function::RandInt,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "clamp" => Function::Clamp,
+            "round" => Function::Round,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "abs" => Function::Abs,
+            _ => return None,
+        })
+    }
+
+    fn arity_ok(self, args: usize) -> bool {
+        match self {
+            Function::Rand | Function::RandInt => args == 2,
+            Function::Min | Function::Max => args >= 1,
+            Function::Clamp => args == 3,
+            Function::Round | Function::Floor | Function::Ceil | Function::Abs => args == 1,
+        }
+    }
+}
+
+impl Formula {
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut parser = FormulaParser { src: s, pos: 0 };
+        let formula = parser.sum()?;
+        parser.skip_whitespace();
+        if parser.pos == s.len() {
+            Ok(formula)
+        } else {
+            Err(parser.error("unexpected trailing input"))
+        }
+    }
+
+    /// The value of a plain number, like `5` or `-0.5`
+    fn literal(&self) -> Option<f64> {
+        match self {
+            &Formula::Number(num) => Some(num),
+            Formula::Neg(inner) => inner.literal().map(|num| -num),
+            _ => None,
+        }
+    }
+}
+
+/// Recursive descent parser for formulas, lowest to highest precedence:
+///
+/// ```text
+/// sum     := product (('+' | '-') product)*
+/// product := unary (('*' | '/' | '%') unary)*
+/// unary   := '-' unary | atom
+/// atom    := number | 'original' | 'ref' '(' path ')' | ident '(' sum (',' sum)* ')'
+///          | '(' sum ')'
+/// ```
+struct FormulaParser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> FormulaParser<'a> {
+    fn error(&self, msg: &str) -> Error {
+        Error::InvalidFormula(format!("{} at column {} of {:?}", msg, self.pos + 1, self.src))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn sum(&mut self) -> Result<Formula, Error> {
+        let mut lhs = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                BinOp::Add
+            } else if self.eat('-') {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Formula::BinOp(op, Box::new(lhs), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Formula, Error> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinOp::Mul
+            } else if self.eat('/') {
+                BinOp::Div
+            } else if self.eat('%') {
+                BinOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Formula::BinOp(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Formula, Error> {
+        if self.eat('-') {
+            Ok(Formula::Neg(Box::new(self.unary()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Formula, Error> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.sum()?;
+                if self.eat(')') {
+                    Ok(inner)
+                } else {
+                    Err(self.error("expected `)`"))
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let num = self.take_while(|c| c.is_ascii_digit() || c == '.' || c == '_');
+                num.replace('_', "")
+                    .parse()
+                    .map(Formula::Number)
+                    .map_err(|_| self.error("invalid number"))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let start = self.pos;
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                if name == "ref" && self.eat('(') {
+                    let path = self.take_while(|c| c != ')').trim();
+                    if !self.eat(')') {
+                        return Err(self.error("expected `)`"));
+                    }
+
+                    return PrcPath::from_str(path).map(Formula::Ref);
+                }
+
+                if !self.eat('(') {
+                    return if name == "original" {
+                        Ok(Formula::Original)
+                    } else {
+                        self.pos = start;
+                        Err(self.error(&format!("unknown variable `{}`", name)))
+                    };
+                }
+
+                let function = match Function::from_name(name) {
+                    Some(function) => function,
+                    None => {
+                        self.pos = start;
+                        return Err(self.error(&format!("unknown function `{}`", name)));
+                    }
+                };
+
+                let mut args = Vec::new();
+                if !self.eat(')') {
+                    loop {
+                        args.push(self.sum()?);
+                        if self.eat(')') {
+                            break;
+                        } else if !self.eat(',') {
+                            return Err(self.error("expected `,` or `)`"));
+                        }
+                    }
+                }
+
+                if function.arity_ok(args.len()) {
+                    // catch ranges that could never be drawn from now rather than when applying
+                    if let [from, to] = args.as_slice() {
+                        let empty = match (function, from.literal(), to.literal()) {
+                            (Function::Rand, Some(from), Some(to)) => from >= to,
+                            (Function::RandInt, Some(from), Some(to)) => from.round() > to.round(),
+                            _ => false,
+                        };
+                        if empty {
+                            self.pos = start;
+                            return Err(self.error(&format!("`{}` range is empty", name)));
+                        }
+                    }
+
+                    Ok(Formula::Call(function, args))
+                } else {
+                    self.pos = start;
+                    Err(self.error(&format!(
+                        "wrong number of arguments ({}) to `{}`",
+                        args.len(),
+                        name
+                    )))
+                }
             }
+            Some(_) => Err(self.error("expected a number, `original`, or a function call")),
+            None => Err(self.error("unexpected end of formula")),
         }
     }
 }
 
 impl Expr {
-    fn from_nodes(mut x: Vec<KdlNode>) -> Result<Self, Error> {
+    fn from_nodes(mut x: Vec<KdlNode>) -> Result<Self, Errors> {
         match x.len() {
-            0 => Err(Error::ExprRequired),
+            0 => Err(Error::ExprRequired.into()),
             1 => {
                 // parse any type
                 match &*x[0].name {
                     "chance" => ChanceStmt::from_nodes(x).map(Expr::Random),
+                    "when" | "if" => WhenStmt::from_nodes(x),
+                    "else" => in_child(0, Err(Error::InvalidWhen(
+                        "`else` has to follow a `when`",
+                    ).into())),
                     "original" => Ok(Expr::Original),
-                    "return" => Return::from_node(x.pop().unwrap()).map(Expr::Return),
-                    name => Err(Error::InvalidExpr(format!(
+                    "shuffle" => in_child(0, Err(Error::InvalidExpr(
+                        "`shuffle` can't be used inside a `chance` or `when`".into(),
+                    ).into())),
+                    "return" => in_child(0, Return::from_node(x.pop().unwrap()).map(Expr::Return)),
+                    name => in_child(0, Err(Error::InvalidExpr(format!(
                         "{} is not a valid expression",
                         name
-                    ))),
+                    )).into())),
                 }
             }
             _ if x[0].name == "chance" => ChanceStmt::from_nodes(x).map(Expr::Random),
-            _ => Err(Error::TooManyExprs),
+            _ if x[0].name == "when" || x[0].name == "if" => WhenStmt::from_nodes(x),
+            _ => in_child(1, Err(Error::TooManyExprs.into())),
+        }
+    }
+}
+
+impl PrcPath {
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::from_str_inner(s).map_err(|err| Error::InvalidPath(s.to_owned(), err))
+    }
+
+    fn from_str_inner(s: &str) -> Result<Self, &'static str> {
+        let mut components = Vec::new();
+        for part in split_outside(s, '.') {
+            // `attack_data[*]` is the same as `attack_data.*`
+            // brackets inside a filter or regex are part of it
+            let bracket = match (part.find('['), part.find('(')) {
+                _ if part.starts_with('/') => None,
+                (Some(start), Some(paren)) if paren < start => None,
+                (start, _) => start,
+            };
+            match bracket {
+                Some(start) => {
+                    let end = part[start..].find(']').ok_or("unmatched `[`")? + start;
+                    components.push(PrcPathComponent::from_str(&part[..start])?);
+                    let inner = format!("{}{}", &part[start + 1..end], &part[end + 1..]);
+                    components.push(PrcPathComponent::from_str(&inner)?);
+                }
+                _ => components.push(PrcPathComponent::from_str(part)?),
+            }
         }
+
+        let recursive = components
+            .iter()
+            .filter(|component| matches!(component, PrcPathComponent::RecursiveWildcard))
+            .count();
+        if recursive > 1 {
+            return Err("paths can only have one `**`");
+        }
+        if let Some(PrcPathComponent::RecursiveWildcard) = components.last() {
+            return Err("`**` has to be followed by the field to find, like `**.damage`");
+        }
+
+        Ok(PrcPath(components))
     }
 }
 
 impl PrcEntry {
-    fn from_node(node: KdlNode) -> Result<Self, Error> {
-        let path = PrcPath(
-            node.name
-                .split('.')
-                .map(PrcPathComponent::from_str)
-                .collect::<Result<_, _>>()?,
-        );
-
-        Expr::from_nodes(node.children).map(move |expr| PrcEntry { path, expr })
+    fn from_node(node: KdlNode) -> Result<Self, Errors> {
+        let path = PrcPath::from_str(&node.name).and_then(|mut path| {
+            match node.properties.get("lenient") {
+                Some(KdlValue::Boolean(true)) => {
+                    for component in path.0.iter_mut() {
+                        match component {
+                            PrcPathComponent::Wildcard => {
+                                *component = PrcPathComponent::LenientWildcard
+                            }
+                            PrcPathComponent::Filter { lenient, .. } => *lenient = true,
+                            _ => {}
+                        }
+                    }
+                }
+                Some(KdlValue::Boolean(false)) | None => {}
+                Some(_) => return Err(Error::InvalidPrcEntry("`lenient` must be a bool")),
+            }
+
+            Ok(path)
+        });
+
+        // `shuffle` works on every match at once, so it can only be used on its own
+        let expr = match node.children.as_slice() {
+            [shuffle] if shuffle.name == "shuffle" => {
+                if shuffle.values.is_empty()
+                    && shuffle.properties.is_empty()
+                    && shuffle.children.is_empty()
+                {
+                    Ok(Expr::Shuffle)
+                } else {
+                    in_child(0, Err(Error::InvalidExpr(
+                        "`shuffle` doesn't take any values, properties, or children".into(),
+                    ).into()))
+                }
+            }
+            _ => Expr::from_nodes(node.children),
+        };
+
+        let mode = Mode::from_properties(&node.properties).and_then(|mode| {
+            match (&expr, mode) {
+                (Ok(Expr::Shuffle), Mode::Once) => Err(Error::InvalidPrcEntry(
+                    "`shuffle` already works on every match at once, it can't use `mode=\"once\"`",
+                )),
+                _ => Ok(mode),
+            }
+        });
+
+        let expr = expr.map(|mut expr| {
+            expr.number_inline_sets(&mut 0);
+            expr
+        });
+
+        // check the expression even if the path is invalid
+        both(path, both(mode, expr)).map(|(path, (mode, expr))| PrcEntry {
+            path,
+            expr,
+            mode,
+            group: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_plain() {
+        assert_eq!(split_outside("a.b.c", '.'), ["a", "b", "c"]);
+        assert_eq!(split_outside("a", '.'), ["a"]);
+        assert_eq!(split_outside("", '.'), [""]);
+        assert_eq!(split_outside("a,b", ','), ["a", "b"]);
+    }
+
+    #[test]
+    fn split_skips_nested() {
+        assert_eq!(split_outside("a.*(b.c==1).d", '.'), ["a", "*(b.c==1)", "d"]);
+        assert_eq!(split_outside("a[b.c].d", '.'), ["a[b.c]", "d"]);
+        assert_eq!(split_outside("*(name==\"a.b\").c", '.'), ["*(name==\"a.b\")", "c"]);
+        assert_eq!(split_outside("a./^b.c$/.d", '.'), ["a", "/^b.c$/", "d"]);
+        assert_eq!(split_outside("id>=3,kind==\"a,b\"", ','), ["id>=3", "kind==\"a,b\""]);
+    }
+
+    #[test]
+    fn split_ranges() {
+        assert_eq!(split_outside("a.2..5.b", '.'), ["a", "2..5", "b"]);
+        assert_eq!(split_outside("a...3.b", '.'), ["a", "..3", "b"]);
+        assert_eq!(split_outside("a.2...b", '.'), ["a", "2..", "b"]);
+        assert_eq!(split_outside("a.-2...b", '.'), ["a", "-2..", "b"]);
+        assert_eq!(split_outside("a.0,4..6.b", '.'), ["a", "0,4..6", "b"]);
+    }
+
+    #[test]
+    fn selections() {
+        assert!(matches!(Selection::from_str("3"), Ok(Selection::Index(3))));
+        assert!(matches!(Selection::from_str("-1"), Ok(Selection::Index(-1))));
+        assert!(matches!(Selection::from_str("2..5"), Ok(Selection::Range(Some(2), Some(5)))));
+        assert!(matches!(Selection::from_str("..3"), Ok(Selection::Range(None, Some(3)))));
+        assert!(matches!(Selection::from_str("-2.."), Ok(Selection::Range(Some(-2), None))));
+        assert!(matches!(Selection::from_str("-3..5"), Ok(Selection::Range(Some(-3), Some(5)))));
+        assert!(Selection::from_str("5..2").is_err());
+        assert!(Selection::from_str("-1..-3").is_err());
+        assert!(Selection::from_str("a").is_err());
+    }
+
+    #[test]
+    fn paths_round_trip() {
+        for &path in &[
+            "attack_data.*.damage",
+            "attack_data.*?.damage",
+            "**.damage",
+            "attack_data.-1.damage",
+            "attack_data.0.damage",
+            "attack_data.*(start_frame>=10).damage",
+            "*_speed",
+            "attack_data.*./^(start|end)_frame$/",
+        ] {
+            assert_eq!(PrcPath::from_str(path).unwrap().to_string(), path);
+        }
+    }
+
+    #[test]
+    fn path_errors() {
+        for &path in &["a.b.", "**", "**.a.**.b", "a.b(c)", "a[*", "a./[/", "x*(id>=3).y"] {
+            assert!(PrcPath::from_str(path).is_err(), "{} should not parse", path);
+        }
+    }
+
+    /// Evaluate a formula made only of numbers and operators
+    fn constant(formula: &Formula) -> f64 {
+        match formula {
+            &Formula::Number(num) => num,
+            Formula::Neg(inner) => -constant(inner),
+            Formula::BinOp(op, lhs, rhs) => {
+                let (lhs, rhs) = (constant(lhs), constant(rhs));
+                match op {
+                    BinOp::Add => lhs + rhs,
+                    BinOp::Sub => lhs - rhs,
+                    BinOp::Mul => lhs * rhs,
+                    BinOp::Div => lhs / rhs,
+                    BinOp::Rem => lhs % rhs,
+                }
+            }
+            _ => panic!("{:?} isn't constant", formula),
+        }
+    }
+
+    fn formula(s: &str) -> f64 {
+        constant(&Formula::from_str(s).unwrap())
+    }
+
+    fn formula_error(s: &str) -> String {
+        match Formula::from_str(s) {
+            Err(Error::InvalidFormula(msg)) => msg,
+            other => panic!("{:?} parsed as {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn formula_precedence() {
+        assert_eq!(formula("1 + 2 * 3"), 7.0);
+        assert_eq!(formula("(1 + 2) * 3"), 9.0);
+        assert_eq!(formula("10 - 4 - 3"), 3.0);
+        assert_eq!(formula("8 / 4 / 2"), 1.0);
+        assert_eq!(formula("7 % 4 * 2"), 6.0);
+        assert_eq!(formula("-2 * -3"), 6.0);
+        assert_eq!(formula("--1"), 1.0);
+        assert_eq!(formula("  1.5+2 "), 3.5);
+    }
+
+    #[test]
+    fn formula_atoms() {
+        assert!(matches!(Formula::from_str("original"), Ok(Formula::Original)));
+        assert!(matches!(Formula::from_str("ref(attack_data.0.damage)"), Ok(Formula::Ref(_))));
+        match Formula::from_str("clamp(original, 0, rand(1, 2))") {
+            Ok(Formula::Call(Function::Clamp, args)) => {
+                assert_eq!(args.len(), 3);
+                assert!(matches!(args[2], Formula::Call(Function::Rand, _)));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn formula_errors() {
+        assert!(formula_error("1 +").contains("unexpected end of formula"));
+        assert!(formula_error("1 2").contains("unexpected trailing input"));
+        assert!(formula_error("foo(1)").contains("unknown function `foo`"));
+        assert!(formula_error("bar").contains("unknown variable `bar`"));
+        assert!(formula_error("min(1").contains("expected `,` or `)`"));
+        assert!(formula_error("rand(1)").contains("wrong number of arguments (1) to `rand`"));
+        assert!(formula_error("rand(5, 5)").contains("`rand` range is empty"));
+        assert!(formula_error("randint(5, -3)").contains("`randint` range is empty"));
+    }
+
+    #[test]
+    fn formula_ranges_checked_only_when_literal() {
+        assert!(Formula::from_str("randint(-3, -3)").is_ok());
+        assert!(Formula::from_str("rand(-2, -1)").is_ok());
+        assert!(Formula::from_str("rand(original, 1)").is_ok());
+        assert!(Formula::from_str("randint(0, 10000000000000000000)").is_ok());
+    }
+
+    fn parse_return(s: &str) -> Result<Return, Errors> {
+        Return::from_node(kdl::parse_document(s).unwrap().remove(0))
+    }
+
+    #[test]
+    fn return_forms() {
+        assert!(matches!(parse_return("return multiply=2"), Ok(Return::Multiply(_))));
+        assert!(matches!(parse_return("return \"scale\" from=1 to=2"), Ok(Return::Scale(..))));
+        assert!(matches!(parse_return("return from=0 to=10 step=2"), Ok(Return::Range(_))));
+        assert!(matches!(parse_return("return set=\"x\" unique=true"), Ok(Return::Set(..))));
+        assert!(matches!(parse_return("return \"x\""), Ok(Return::Constant(_))));
+    }
+
+    #[test]
+    fn return_property_errors() {
+        for &ret in &[
+            "return multiply=2 add=100",
+            "return set=\"x\" multiply=3",
+            "return expr=\"original\" hash40=\"a\"",
+            "return from=0 to=10 multiply=2",
+            "return \"scale\" from=1 to=2 step=1",
+            "return from=0 to=10 stpe=2",
+            "return \"scale\"",
+        ] {
+            assert!(parse_return(ret).is_err(), "{} should not parse", ret);
+        }
+        let error = parse_return("return from=0 to=10 stpe=2").unwrap_err().to_string();
+        assert!(error.contains("Invalid property in return"), "{}", error);
+        let error = parse_return("return multiply=2 add=100").unwrap_err().to_string();
+        assert!(error.contains("only use one of"), "{}", error);
     }
 }
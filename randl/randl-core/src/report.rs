@@ -0,0 +1,97 @@
+//! A record of what applying an entry did, for spoiler logs and debugging configs.
+
+use std::fmt;
+
+use prc::ParamKind;
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub changes: Vec<Change>,
+    /// Every path with a lenient wildcard (`*?`) that skipped some of its matches
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    /// The path of the prc file in game
+    pub prc_path: String,
+    /// The path as written in the config
+    pub path: String,
+    /// How many matches of the wildcards were skipped
+    pub count: usize,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: skipped {} match{}",
+            self.prc_path,
+            self.path,
+            self.count,
+            if self.count == 1 { "" } else { "es" }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// The path of the prc file in game
+    pub prc_path: String,
+    /// The param that was edited, with wildcards replaced by what they matched
+    /// (`attack_data.3.start_frame`)
+    pub param: String,
+    pub old: ParamKind,
+    pub new: ParamKind,
+    /// The index of the `chance` taken at each level of nesting, in declaration order
+    pub chances: Vec<usize>,
+}
+
+impl Change {
+    pub fn changed(&self) -> bool {
+        DisplayParam(&self.old).to_string() != DisplayParam(&self.new).to_string()
+    }
+}
+
+/// Displays a single (non-list, non-struct) param value
+pub struct DisplayParam<'a>(pub &'a ParamKind);
+
+impl fmt::Display for DisplayParam<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            ParamKind::Bool(b) => write!(f, "{}", b),
+            ParamKind::I8(i) => write!(f, "{}", i),
+            ParamKind::U8(i) => write!(f, "{}", i),
+            ParamKind::I16(i) => write!(f, "{}", i),
+            ParamKind::U16(i) => write!(f, "{}", i),
+            ParamKind::I32(i) => write!(f, "{}", i),
+            ParamKind::U32(i) => write!(f, "{}", i),
+            ParamKind::Float(x) => write!(f, "{}", x),
+            ParamKind::Hash(hash) => write!(f, "{}", hash),
+            ParamKind::Str(s) => write!(f, "{:?}", s),
+            ParamKind::List(_) => f.write_str("[list]"),
+            ParamKind::Struct(_) => f.write_str("[struct]"),
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: ", self.prc_path, self.param)?;
+        if self.changed() {
+            write!(f, "{} -> {}", DisplayParam(&self.old), DisplayParam(&self.new))?;
+        } else {
+            write!(f, "{} (unchanged)", DisplayParam(&self.old))?;
+        }
+
+        for (i, chance) in self.chances.iter().enumerate() {
+            f.write_str(if i == 0 { " [chance " } else { " > " })?;
+            write!(f, "#{}", chance + 1)?;
+        }
+        if !self.chances.is_empty() {
+            f.write_str("]")?;
+        }
+
+        Ok(())
+    }
+}
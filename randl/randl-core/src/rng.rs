@@ -0,0 +1,281 @@
+//! A small, portable RNG and the sampling functions built on top of it.
+//!
+//! Everything here is implemented by hand rather than relying on `rand`'s `StdRng` or
+//! distributions, as those make no promises about producing the same values across versions or
+//! platforms. A given seed needs to produce identical params on both the CLI and the plugin, so
+//! `ln`, `cos` and friends come from `libm` too, as the platform math libraries can round
+//! differently.
+
+use rand::{Error, RngCore, SeedableRng};
+
+/// xoshiro256**, seeded using splitmix64
+#[derive(Debug, Clone)]
+pub struct RandlRng {
+    state: [u64; 4],
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl RandlRng {
+    pub fn new(seed: u64) -> Self {
+        let mut sm = seed;
+        Self {
+            state: [
+                splitmix64(&mut sm),
+                splitmix64(&mut sm),
+                splitmix64(&mut sm),
+                splitmix64(&mut sm),
+            ],
+        }
+    }
+}
+
+impl RngCore for RandlRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+
+        result
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for RandlRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::new(u64::from_le_bytes(seed))
+    }
+
+    fn seed_from_u64(seed: u64) -> Self {
+        Self::new(seed)
+    }
+}
+
+/// Derives a seed from a global seed and a series of labels, so that each label gets its own
+/// independent stream of random numbers. Uses FNV-1a, which (unlike std's hashers) is guaranteed
+/// to stay the same across Rust versions.
+#[derive(Debug, Clone)]
+pub(crate) struct SeedHasher(u64);
+
+impl SeedHasher {
+    pub(crate) fn new(seed: u64) -> Self {
+        let mut seed = seed;
+        SeedHasher(splitmix64(&mut seed))
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.0 ^= byte as u64;
+        self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    pub(crate) fn u64(mut self, x: u64) -> Self {
+        for &byte in x.to_le_bytes().iter() {
+            self.byte(byte);
+        }
+        self
+    }
+
+    pub(crate) fn str(mut self, s: &str) -> Self {
+        // include the length so ("ab", "c") and ("a", "bc") don't collide
+        self = self.u64(s.len() as u64);
+        for byte in s.bytes() {
+            self.byte(byte);
+        }
+        self
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        let mut state = self.0;
+        splitmix64(&mut state)
+    }
+}
+
+/// A float in the range `[0, 1)`
+pub(crate) fn gen_float(rng: &mut dyn RngCore) -> f64 {
+    (rng.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+/// A float in the range `[from, to)`
+pub(crate) fn gen_float_range(rng: &mut dyn RngCore, from: f64, to: f64) -> f64 {
+    from + (to - from) * gen_float(rng)
+}
+
+/// An integer in the range `[from, to)`. `from` must be less than `to`, except that `to` may have
+/// wrapped around past `i64::MAX`, so `gen_int_range(rng, 0, i64::MAX.wrapping_add(1))` covers
+/// every non-negative integer, and `from == to` covers every integer.
+pub(crate) fn gen_int_range(rng: &mut dyn RngCore, from: i64, to: i64) -> i64 {
+    let span = to.wrapping_sub(from) as u64;
+    if span == 0 {
+        return rng.next_u64() as i64;
+    }
+
+    // reject the top partial bucket so every value is equally likely
+    let zone = u64::MAX - (u64::MAX - span + 1) % span;
+    loop {
+        let x = rng.next_u64();
+        if x <= zone {
+            return from.wrapping_add((x % span) as i64);
+        }
+    }
+}
+
+/// An index into a slice of length `len`. `len` must not be zero.
+pub(crate) fn gen_index(rng: &mut dyn RngCore, len: usize) -> usize {
+    gen_int_range(rng, 0, len as i64) as usize
+}
+
+/// An index into `weights`, each picked with a probability proportional to its weight. The
+/// weights must not all be zero.
+pub(crate) fn gen_weighted_index(rng: &mut dyn RngCore, weights: &[f64]) -> usize {
+    let total: f64 = weights.iter().sum();
+    let mut roll = gen_float(rng) * total;
+    for (i, &weight) in weights.iter().enumerate() {
+        if roll < weight {
+            return i;
+        }
+        roll -= weight;
+    }
+
+    // float error can leave a sliver past the last value, give it to the last non-zero weight
+    weights.iter().rposition(|&weight| weight > 0.0).unwrap_or(0)
+}
+
+/// A float from the standard normal distribution, using the Box-Muller transform
+pub(crate) fn gen_normal(rng: &mut dyn RngCore) -> f64 {
+    // `1 - x` keeps the log away from 0
+    let radius = (-2.0 * libm::log(1.0 - gen_float(rng))).sqrt();
+    let angle = 2.0 * std::f64::consts::PI * gen_float(rng);
+    radius * libm::cos(angle)
+}
+
+/// A float from a gamma distribution with the given shape and a scale of 1, using
+/// Marsaglia and Tsang's method
+pub(crate) fn gen_gamma(rng: &mut dyn RngCore, shape: f64) -> f64 {
+    if shape < 1.0 {
+        // boost the shape above 1 and correct for it afterwards
+        let boost = libm::pow(1.0 - gen_float(rng), 1.0 / shape);
+        return gen_gamma(rng, shape + 1.0) * boost;
+    }
+
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = gen_normal(rng);
+        let v = 1.0 + c * x;
+        if v <= 0.0 {
+            continue;
+        }
+
+        let v = v * v * v;
+        let u = 1.0 - gen_float(rng);
+        if libm::log(u) < 0.5 * x * x + d - d * v + d * libm::log(v) {
+            return d * v;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // expected values come from a separate implementation of the reference algorithms. if any of
+    // these change, every seed shared so far gives different results
+    #[test]
+    fn splitmix64_matches_reference() {
+        let mut state = 0;
+        assert_eq!(splitmix64(&mut state), 0xe220_a839_7b1d_cdaf);
+    }
+
+    #[test]
+    fn rng_known_answers() {
+        let mut rng = RandlRng::new(0);
+        assert_eq!(rng.next_u64(), 0x99ec_5f36_cb75_f2b4);
+        assert_eq!(rng.next_u64(), 0xbf6e_1f78_4956_452a);
+        assert_eq!(rng.next_u64(), 0x1a5f_849d_4933_e6e0);
+
+        let mut rng = RandlRng::seed_from_u64(12345);
+        assert_eq!(rng.next_u64(), 0xbe6a_3637_4160_d49b);
+        assert_eq!(rng.next_u64(), 0x214a_aa06_37a6_88c6);
+        assert_eq!(rng.next_u64(), 0xf69d_16de_9954_d388);
+    }
+
+    #[test]
+    fn seed_hasher_known_answers() {
+        assert_eq!(SeedHasher::new(0).finish(), 0xa706_dd2f_4d19_7e6f);
+        assert_eq!(
+            SeedHasher::new(42).str("fighter/mario/param/vl.prc").u64(7).finish(),
+            0x80a2_1cc5_c50a_6b96,
+        );
+    }
+
+    #[test]
+    fn seed_hasher_separates_strings() {
+        let a = SeedHasher::new(0).str("ab").str("c").finish();
+        let b = SeedHasher::new(0).str("a").str("bc").finish();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn int_range_known_answers() {
+        let mut rng = RandlRng::new(7);
+        let rolls: Vec<i64> = (0..8).map(|_| gen_int_range(&mut rng, -5, 5)).collect();
+        assert_eq!(rolls, [-1, -1, 3, -1, -1, -4, 1, 1]);
+    }
+
+    #[test]
+    fn int_range_full_width() {
+        // `from == to` is every integer
+        let mut rng = RandlRng::new(7);
+        assert_eq!(gen_int_range(&mut rng, 3, 3), -5_523_389_002_881_075_622);
+        assert_eq!(gen_int_range(&mut rng, 3, 3), 5_142_052_590_334_782_674);
+
+        // `to` wrapping past i64::MAX is every non-negative integer
+        let mut rng = RandlRng::new(7);
+        let to = i64::MAX.wrapping_add(1);
+        assert_eq!(gen_int_range(&mut rng, 0, to), 3_699_983_033_973_700_186);
+        assert_eq!(gen_int_range(&mut rng, 0, to), 5_142_052_590_334_782_674);
+    }
+
+    #[test]
+    fn int_range_stays_in_bounds() {
+        let mut rng = RandlRng::new(1);
+        for _ in 0..1000 {
+            let x = gen_int_range(&mut rng, -3, 4);
+            assert!((-3..4).contains(&x));
+        }
+        for _ in 0..1000 {
+            let x = gen_int_range(&mut rng, i64::MAX - 1, i64::MAX.wrapping_add(1));
+            assert!(x >= i64::MAX - 1);
+        }
+    }
+}
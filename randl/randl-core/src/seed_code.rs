@@ -0,0 +1,129 @@
+//! Short, shareable codes identifying a run: the seed plus a fingerprint of the configs used.
+//!
+//! Codes are 16 characters of Crockford base32, split into groups of 4 (`3ZK1-8Q0M-TT2C-9VXA`).
+//! The first 64 bits are the seed and the last 16 are the fingerprint.
+
+use std::{fmt, str::FromStr};
+
+use crate::{RandlFile, SeedCodeError};
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CODE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeedCode {
+    pub seed: u64,
+    pub fingerprint: u16,
+}
+
+/// A fingerprint of every config being used. Doesn't depend on the order of `files`.
+pub fn fingerprint(files: &[RandlFile]) -> u64 {
+    files
+        .iter()
+        .fold(0, |fingerprint, file| fingerprint.wrapping_add(file.fingerprint))
+}
+
+impl SeedCode {
+    pub fn new(seed: u64, files: &[RandlFile]) -> Self {
+        Self {
+            seed,
+            fingerprint: fingerprint(files) as u16,
+        }
+    }
+
+    /// Whether the code was made using the same configs as `files`
+    pub fn matches(&self, files: &[RandlFile]) -> bool {
+        self.fingerprint == fingerprint(files) as u16
+    }
+}
+
+impl fmt::Display for SeedCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = ((self.seed as u128) << 16) | self.fingerprint as u128;
+        for i in 0..CODE_LEN {
+            if i != 0 && i % 4 == 0 {
+                f.write_str("-")?;
+            }
+            let digit = (bits >> (5 * (CODE_LEN - 1 - i))) & 0x1f;
+            write!(f, "{}", ALPHABET[digit as usize] as char)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for SeedCode {
+    type Err = SeedCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bits = 0u128;
+        let mut len = 0;
+        for c in s.trim().chars().filter(|&c| c != '-') {
+            // Crockford base32 treats commonly confused letters as their look-alike digits
+            let digit = match c.to_ascii_uppercase() {
+                'O' => 0,
+                'I' | 'L' => 1,
+                c => ALPHABET
+                    .iter()
+                    .position(|&x| x as char == c)
+                    .ok_or(SeedCodeError::InvalidChar(c))?,
+            };
+            bits = (bits << 5) | digit as u128;
+            len += 1;
+        }
+
+        if len != CODE_LEN {
+            return Err(SeedCodeError::InvalidLength);
+        }
+
+        Ok(SeedCode {
+            seed: (bits >> 16) as u64,
+            fingerprint: bits as u16,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_known_answer() {
+        let code = SeedCode { seed: 1234, fingerprint: 0xbeef };
+        assert_eq!(code.to_string(), "0000-0000-002D-5FQF");
+
+        let code = SeedCode { seed: u64::MAX, fingerprint: u16::MAX };
+        assert_eq!(code.to_string(), "ZZZZ-ZZZZ-ZZZZ-ZZZZ");
+    }
+
+    #[test]
+    fn round_trip() {
+        let codes = [(0, 0), (1234, 0xbeef), (u64::MAX, 1), (0x0123_4567_89ab_cdef, 42)];
+        for &(seed, fingerprint) in &codes {
+            let code = SeedCode { seed, fingerprint };
+            assert_eq!(code.to_string().parse::<SeedCode>().unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn parse_is_forgiving() {
+        let code = SeedCode { seed: 1234, fingerprint: 0xbeef };
+        assert_eq!(" 0000-0000-002d-5fqf\n".parse::<SeedCode>().unwrap(), code);
+        assert_eq!("00000000002D5FQF".parse::<SeedCode>().unwrap(), code);
+        // look-alike letters read as digits
+        assert_eq!("OOOO-OOOO-OO2D-5FQF".parse::<SeedCode>().unwrap(), code);
+        assert_eq!(
+            "IIII-LLLL-iiii-llll".parse::<SeedCode>().unwrap(),
+            "1111-1111-1111-1111".parse::<SeedCode>().unwrap(),
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!("0000-0000".parse::<SeedCode>(), Err(SeedCodeError::InvalidLength)));
+        assert!(matches!(
+            "0000-0000-002D-5FQU".parse::<SeedCode>(),
+            Err(SeedCodeError::InvalidChar('U'))
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+//! Finding where nodes are in a config's source.
+//!
+//! `kdl` doesn't keep track of where the nodes it parses came from, so once a document has parsed
+//! successfully it's scanned again here to find each node. Only the start of every node and
+//! the nesting matter, so the scanner just skips over values, properties, and comments.
+
+use std::fmt;
+
+/// Where in a config a node or error is
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+    /// The length of the span in characters, at least 1
+    pub len: usize,
+    /// The full line of source the span starts on
+    pub source_line: String,
+}
+
+impl Span {
+    pub(crate) fn new(src: &str, start: usize, end: usize) -> Self {
+        let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[start..]
+            .find(&['\n', '\r'][..])
+            .map(|i| start + i)
+            .unwrap_or_else(|| src.len());
+        let mut end = end.min(line_end).max(start);
+        while !src.is_char_boundary(end) {
+            end += 1;
+        }
+
+        Span {
+            line: src[..start].matches('\n').count() + 1,
+            column: src[line_start..start].chars().count() + 1,
+            len: src[start..end].chars().count().max(1),
+            source_line: src[line_start..line_end].to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The location of a node, mirroring the tree of `KdlNode`s
+#[derive(Debug, Clone)]
+pub(crate) struct NodeSpan {
+    /// The node's name followed by its first argument, if it has one (`file "fighter/..."`)
+    pub(crate) label: String,
+    /// Byte range of the node's name
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) children: Vec<NodeSpan>,
+}
+
+/// Find every node in a document that `kdl` has already parsed
+pub(crate) fn node_spans(src: &str) -> Vec<NodeSpan> {
+    let mut scanner = Scanner { src, pos: 0 };
+    scanner.nodes()
+}
+
+struct Scanner<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn line_comment(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' || c == '\r' {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    fn block_comment(&mut self) {
+        // block comments nest
+        let mut depth = 1;
+        while depth > 0 && self.peek().is_some() {
+            if self.eat("/*") {
+                depth += 1;
+            } else if self.eat("*/") {
+                depth -= 1;
+            } else {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, block comments, and escaped newlines within a node
+    fn node_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() && c != '\n' && c != '\r' => self.bump(),
+                Some('\\') => {
+                    // line continuation
+                    self.bump();
+                    while let Some(c) = self.peek() {
+                        if c == '\n' || c == '\r' {
+                            break;
+                        } else if self.rest().starts_with("//") {
+                            self.line_comment();
+                        } else {
+                            self.bump();
+                        }
+                    }
+                    self.eat("\r");
+                    self.eat("\n");
+                }
+                Some('/') if self.eat("/*") => self.block_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    /// Skip everything allowed between nodes
+    fn line_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() || c == ';' || c == '\u{feff}' => self.bump(),
+                Some('/') if self.eat("//") => self.line_comment(),
+                Some('/') if self.eat("/*") => self.block_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn string(&mut self) {
+        self.bump();
+        while let Some(c) = self.peek() {
+            self.bump();
+            match c {
+                '\\' => self.bump(),
+                '"' => break,
+                _ => {}
+            }
+        }
+    }
+
+    fn raw_string(&mut self) {
+        self.bump();
+        let mut hashes = 0;
+        while self.eat("#") {
+            hashes += 1;
+        }
+        self.bump();
+
+        let end = format!("\"{}", "#".repeat(hashes));
+        while self.peek().is_some() && !self.eat(&end) {
+            self.bump();
+        }
+    }
+
+    /// Skip an identifier, string, or bare value
+    fn token(&mut self) {
+        let rest = self.rest();
+        if rest.starts_with('"') {
+            self.string();
+        } else if rest.starts_with("r\"") || rest.starts_with("r#") {
+            self.raw_string();
+        } else {
+            while let Some(c) = self.peek() {
+                if c.is_whitespace() || "\\/(){}<>;[]=,\"".contains(c) {
+                    break;
+                }
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip a type annotation like `(u8)`, if there is one
+    fn annotation(&mut self) {
+        if self.eat("(") {
+            self.token();
+            self.eat(")");
+        }
+    }
+
+    /// Skip an argument or property, returning whether it was an argument
+    fn entry(&mut self) -> bool {
+        self.annotation();
+        let start = self.pos;
+        self.token();
+        if self.pos == start {
+            // not something we understand, skip it rather than looping forever
+            self.bump();
+        }
+
+        if self.eat("=") {
+            self.annotation();
+            self.token();
+            false
+        } else {
+            true
+        }
+    }
+
+    fn nodes(&mut self) -> Vec<NodeSpan> {
+        let mut nodes = Vec::new();
+        loop {
+            self.line_space();
+            if self.peek().is_none() || self.peek() == Some('}') {
+                break;
+            }
+
+            let commented = self.eat("/-");
+            if commented {
+                self.node_space();
+            }
+            self.annotation();
+
+            let start = self.pos;
+            self.token();
+            let end = self.pos;
+            let mut label = self.src[start..end].to_owned();
+            let mut children = Vec::new();
+            let mut first = true;
+
+            loop {
+                self.node_space();
+                match self.peek() {
+                    None | Some('\n') | Some('\r') | Some(';') | Some('}') => break,
+                    Some('/') if self.eat("//") => {
+                        self.line_comment();
+                        break;
+                    }
+                    Some('{') => {
+                        self.bump();
+                        children = self.nodes();
+                        self.eat("}");
+                    }
+                    Some('/') if self.eat("/-") => {
+                        self.node_space();
+                        if self.eat("{") {
+                            self.nodes();
+                            self.eat("}");
+                        } else {
+                            self.entry();
+                        }
+                    }
+                    Some(_) => {
+                        let arg_start = self.pos;
+                        if self.entry() && first {
+                            label.push(' ');
+                            label.push_str(&self.src[arg_start..self.pos]);
+                        }
+                        first = false;
+                    }
+                }
+            }
+
+            if !commented {
+                nodes.push(NodeSpan {
+                    label,
+                    start,
+                    end,
+                    children,
+                });
+            }
+        }
+
+        nodes
+    }
+}